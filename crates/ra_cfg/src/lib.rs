@@ -46,4 +46,10 @@ impl CfgOptions {
     pub fn insert_key_value(&mut self, key: SmolStr, value: SmolStr) {
         self.key_values.insert((key, value));
     }
+
+    /// Adds all options from `other`.
+    pub fn extend(&mut self, other: &CfgOptions) {
+        self.atoms.extend(other.atoms.iter().cloned());
+        self.key_values.extend(other.key_values.iter().cloned());
+    }
 }
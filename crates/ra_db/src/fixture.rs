@@ -161,25 +161,30 @@ fn with_files(db: &mut dyn SourceDatabaseExt, fixture: &str) -> Option<FilePosit
     let mut crates = FxHashMap::default();
     let mut crate_deps = Vec::new();
     let mut default_crate_root: Option<FileId> = None;
+    let mut default_cfg = CfgOptions::default();
 
     let mut source_root = SourceRoot::new_local();
     let mut source_root_id = WORKSPACE;
     let mut source_root_prefix: RelativePathBuf = "/".into();
+    let mut source_root_cfg = CfgOptions::default();
     let mut file_id = FileId(0);
 
     let mut file_position = None;
 
     for entry in fixture.iter() {
         let meta = match ParsedMeta::from(&entry.meta) {
-            ParsedMeta::Root { path } => {
+            ParsedMeta::Root { path, cfg } => {
                 let source_root = std::mem::replace(&mut source_root, SourceRoot::new_local());
                 db.set_source_root(source_root_id, Arc::new(source_root));
                 source_root_id.0 += 1;
                 source_root_prefix = path;
+                source_root_cfg = cfg;
                 continue;
             }
             ParsedMeta::File(it) => it,
         };
+        let mut cfg = source_root_cfg.clone();
+        cfg.extend(&meta.cfg);
         assert!(meta.path.starts_with(&source_root_prefix));
 
         if let Some(krate) = meta.krate {
@@ -187,19 +192,21 @@ fn with_files(db: &mut dyn SourceDatabaseExt, fixture: &str) -> Option<FilePosit
                 file_id,
                 meta.edition,
                 Some(CrateName::new(&krate).unwrap()),
-                meta.cfg,
+                cfg,
                 meta.env,
                 Default::default(),
                 Default::default(),
             );
             let prev = crates.insert(krate.clone(), crate_id);
             assert!(prev.is_none());
-            for dep in meta.deps {
-                crate_deps.push((krate.clone(), dep))
+            for (alias, actual) in meta.deps {
+                let actual = actual.unwrap_or_else(|| alias.clone());
+                crate_deps.push((krate.clone(), alias, actual))
             }
         } else if meta.path == "/main.rs" || meta.path == "/lib.rs" {
             assert!(default_crate_root.is_none());
             default_crate_root = Some(file_id);
+            default_cfg = cfg;
         }
 
         let text = if entry.text.contains(CURSOR_MARKER) {
@@ -225,16 +232,16 @@ fn with_files(db: &mut dyn SourceDatabaseExt, fixture: &str) -> Option<FilePosit
             crate_root,
             Edition::Edition2018,
             None,
-            CfgOptions::default(),
+            default_cfg,
             Env::default(),
             Default::default(),
             Default::default(),
         );
     } else {
-        for (from, to) in crate_deps {
+        for (from, alias, to) in crate_deps {
             let from_id = crates[&from];
             let to_id = crates[&to];
-            crate_graph.add_dep(from_id, CrateName::new(&to).unwrap(), to_id).unwrap();
+            crate_graph.add_dep(from_id, CrateName::new(&alias).unwrap(), to_id).unwrap();
         }
     }
 
@@ -245,14 +252,14 @@ fn with_files(db: &mut dyn SourceDatabaseExt, fixture: &str) -> Option<FilePosit
 }
 
 enum ParsedMeta {
-    Root { path: RelativePathBuf },
+    Root { path: RelativePathBuf, cfg: CfgOptions },
     File(FileMeta),
 }
 
 struct FileMeta {
     path: RelativePathBuf,
     krate: Option<String>,
-    deps: Vec<String>,
+    deps: Vec<(String, Option<String>)>,
     cfg: CfgOptions,
     edition: Edition,
     env: Env,
@@ -261,10 +268,10 @@ struct FileMeta {
 impl From<&FixtureMeta> for ParsedMeta {
     fn from(meta: &FixtureMeta) -> Self {
         match meta {
-            FixtureMeta::Root { path } => {
+            FixtureMeta::Root { path, cfg } => {
                 // `Self::Root` causes a false warning: 'variant is never constructed: `Root` '
                 // see https://github.com/rust-lang/rust/issues/69018
-                ParsedMeta::Root { path: path.to_owned() }
+                ParsedMeta::Root { path: path.to_owned(), cfg: cfg.to_owned() }
             }
             FixtureMeta::File(f) => Self::File(FileMeta {
                 path: f.path.to_owned(),
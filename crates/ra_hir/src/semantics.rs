@@ -236,6 +236,12 @@ impl<'db, DB: HirDatabase> Semantics<'db, DB> {
         self.analyze(field.syntax()).resolve_record_field_pat(self.db, field)
     }
 
+    /// Resolves the field bound by a shorthand record pattern, like `x` in
+    /// `let Foo { x, .. } = foo;`.
+    pub fn resolve_record_field_pat_shorthand(&self, pat: &ast::BindPat) -> Option<Field> {
+        self.analyze(pat.syntax()).resolve_record_field_pat_shorthand(self.db, pat)
+    }
+
     pub fn resolve_macro_call(&self, macro_call: &ast::MacroCall) -> Option<MacroDef> {
         let sa = self.analyze(macro_call.syntax());
         let macro_call = self.find_file(macro_call.syntax().clone()).with_value(macro_call);
@@ -401,6 +407,7 @@ to_def_impls![
     (crate::TypeParam, ast::TypeParam, type_param_to_def),
     (crate::MacroDef, ast::MacroCall, macro_call_to_def), // this one is dubious, not all calls are macros
     (crate::Local, ast::BindPat, bind_pat_to_def),
+    (crate::Local, ast::SelfParam, self_param_to_def),
 ];
 
 fn find_root(node: &SyntaxNode) -> SyntaxNode {
@@ -178,6 +178,16 @@ impl SourceAnalyzer {
         Some(struct_field.into())
     }
 
+    pub(crate) fn resolve_record_field_pat_shorthand(
+        &self,
+        _db: &dyn HirDatabase,
+        pat: &ast::BindPat,
+    ) -> Option<Field> {
+        let pat_id = self.pat_id(&pat.clone().into())?;
+        let struct_field = self.infer.as_ref()?.record_field_pat_resolution(pat_id)?;
+        Some(struct_field.into())
+    }
+
     pub(crate) fn resolve_macro_call(
         &self,
         db: &dyn HirDatabase,
@@ -211,16 +221,20 @@ impl SourceAnalyzer {
         db: &dyn HirDatabase,
         path: &ast::Path,
     ) -> Option<PathResolution> {
+        // Paths in array lengths of types, like `N` in `[u8; N]`, are not
+        // part of the body, so fall back to plain name resolution for them.
         if let Some(path_expr) = path.syntax().parent().and_then(ast::PathExpr::cast) {
-            let expr_id = self.expr_id(db, &path_expr.into())?;
-            if let Some(assoc) = self.infer.as_ref()?.assoc_resolutions_for_expr(expr_id) {
-                return Some(PathResolution::AssocItem(assoc.into()));
+            if let Some(expr_id) = self.expr_id(db, &path_expr.into()) {
+                if let Some(assoc) = self.infer.as_ref()?.assoc_resolutions_for_expr(expr_id) {
+                    return Some(PathResolution::AssocItem(assoc.into()));
+                }
             }
         }
         if let Some(path_pat) = path.syntax().parent().and_then(ast::PathPat::cast) {
-            let pat_id = self.pat_id(&path_pat.into())?;
-            if let Some(assoc) = self.infer.as_ref()?.assoc_resolutions_for_pat(pat_id) {
-                return Some(PathResolution::AssocItem(assoc.into()));
+            if let Some(pat_id) = self.pat_id(&path_pat.into()) {
+                if let Some(assoc) = self.infer.as_ref()?.assoc_resolutions_for_pat(pat_id) {
+                    return Some(PathResolution::AssocItem(assoc.into()));
+                }
             }
         }
         // This must be a normal source file rather than macro file.
@@ -403,8 +417,9 @@ pub(crate) fn resolve_hir_path(
     resolver: &Resolver,
     path: &crate::Path,
 ) -> Option<PathResolution> {
-    let types =
-        resolver.resolve_path_in_type_ns_fully(db.upcast(), path.mod_path()).map(|ty| match ty {
+    let types = resolver
+        .resolve_path_in_type_ns_fully(db.upcast(), path.mod_path())
+        .map(|ty| match ty {
             TypeNs::SelfType(it) => PathResolution::SelfType(it.into()),
             TypeNs::GenericParam(id) => PathResolution::TypeParam(TypeParam { id }),
             TypeNs::AdtSelfType(it) | TypeNs::AdtId(it) => {
@@ -414,7 +429,8 @@ pub(crate) fn resolve_hir_path(
             TypeNs::TypeAliasId(it) => PathResolution::Def(TypeAlias::from(it).into()),
             TypeNs::BuiltinType(it) => PathResolution::Def(it.into()),
             TypeNs::TraitId(it) => PathResolution::Def(Trait::from(it).into()),
-        });
+        })
+        .or_else(|| resolve_trait_assoc_type(db, resolver, path));
 
     let body_owner = resolver.body_owner();
     let values =
@@ -446,6 +462,23 @@ pub(crate) fn resolve_hir_path(
     })
 }
 
+/// Resolves `<T as Trait>::Assoc`, which is desugared to `Trait::Assoc`, to
+/// the associated type of the trait.
+fn resolve_trait_assoc_type(
+    db: &dyn HirDatabase,
+    resolver: &Resolver,
+    path: &crate::Path,
+) -> Option<PathResolution> {
+    let segments = &path.mod_path().segments;
+    match resolver.resolve_path_in_type_ns(db.upcast(), path.mod_path())? {
+        (TypeNs::TraitId(trait_id), Some(idx)) if idx + 1 == segments.len() => {
+            let alias = db.trait_data(trait_id).associated_type_by_name(&segments[idx])?;
+            Some(PathResolution::Def(TypeAlias::from(alias).into()))
+        }
+        _ => None,
+    }
+}
+
 /// Resolves a path where we know it is a qualifier of another path.
 ///
 /// For example, if we have:
@@ -3,7 +3,7 @@
 use indexmap::IndexMap;
 
 use hir::Semantics;
-use ra_ide_db::RootDatabase;
+use ra_ide_db::{search::ReferenceKind, RootDatabase};
 use ra_syntax::{ast, match_ast, AstNode, TextRange};
 
 use crate::{
@@ -42,11 +42,15 @@ pub(crate) fn incoming_calls(db: &RootDatabase, position: FilePosition) -> Optio
     // 1. Find all refs
     // 2. Loop through refs and determine unique fndef. This will become our `from: CallHierarchyItem,` in the reply.
     // 3. Add ranges relative to the start of the fndef.
-    let refs = references::find_all_refs(db, position, None)?;
+    let refs = references::find_all_refs(db, position, &FindAllRefsConfig::default())?;
 
     let mut calls = CallLocations::default();
 
     for reference in refs.info.references() {
+        // Trait method implementations are not call sites.
+        if reference.kind == ReferenceKind::TraitImplItem {
+            continue;
+        }
         let file_id = reference.file_range.file_id;
         let file = sema.parse(file_id);
         let file = file.syntax();
@@ -245,6 +249,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_call_hierarchy_skips_trait_method_decl() {
+        check_hierarchy(
+            r#"
+            //- /lib.rs
+            trait Tr { fn callee(&self); }
+            struct S;
+            impl Tr for S { fn callee(&self) {} }
+            fn caller(s: S) {
+                s.call<|>ee();
+            }
+            "#,
+            "callee FN_DEF FileId(1) 57..76 60..66",
+            &["caller FN_DEF FileId(1) 79..114 82..88 : [103..109]"],
+            &[],
+        );
+    }
+
     #[test]
     fn test_call_hierarchy_in_tests_mod() {
         check_hierarchy(
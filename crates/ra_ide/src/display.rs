@@ -15,7 +15,7 @@ use ra_syntax::{
 use stdx::format_to;
 
 pub use function_signature::FunctionSignature;
-pub use navigation_target::NavigationTarget;
+pub use navigation_target::{NavSpec, NavigationTarget};
 pub use structure::{file_structure, StructureNode};
 
 pub(crate) use navigation_target::{ToNav, TryToNav};
@@ -33,6 +33,17 @@ pub struct NavigationTarget {
     docs: Option<String>,
 }
 
+/// The parts of a `NavigationTarget` which tests usually care about, see
+/// `NavigationTarget::matches_spec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NavSpec {
+    pub name: SmolStr,
+    pub kind: SyntaxKind,
+    pub file_id: FileId,
+    pub full_range: TextRange,
+    pub focus_range: Option<TextRange>,
+}
+
 pub(crate) trait ToNav {
     fn to_nav(&self, db: &RootDatabase) -> NavigationTarget;
 }
@@ -88,6 +99,16 @@ impl NavigationTarget {
         self.focus_range
     }
 
+    /// Checks the name, kind, file and ranges of the target against `spec`.
+    /// Docs, description and container name are ignored.
+    pub fn matches_spec(&self, spec: &NavSpec) -> bool {
+        self.name == spec.name
+            && self.kind == spec.kind
+            && self.file_id == spec.file_id
+            && self.full_range == spec.full_range
+            && self.focus_range == spec.focus_range
+    }
+
     pub(crate) fn from_module_to_decl(db: &RootDatabase, module: hir::Module) -> NavigationTarget {
         let name = module.name(db).map(|it| it.to_string().into()).unwrap_or_default();
         if let Some(src) = module.declaration_source(db) {
@@ -129,6 +150,24 @@ impl NavigationTarget {
         buf
     }
 
+    /// Lifetime parameters don't have an `ast::Name`, so the lifetime token
+    /// itself is used as the name.
+    pub(crate) fn from_lifetime_param(
+        file_id: FileId,
+        param: &ast::LifetimeParam,
+    ) -> Option<NavigationTarget> {
+        let lifetime = param.lifetime_token()?;
+        Some(NavigationTarget::from_syntax(
+            file_id,
+            lifetime.text().clone(),
+            Some(lifetime.text_range()),
+            param.syntax().text_range(),
+            param.syntax().kind(),
+            None,
+            None,
+        ))
+    }
+
     /// Allows `NavigationTarget` to be created from a `NameOwner`
     fn from_named(
         db: &RootDatabase,
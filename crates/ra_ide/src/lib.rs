@@ -63,12 +63,15 @@ pub use crate::{
         CompletionConfig, CompletionItem, CompletionItemKind, CompletionScore, InsertTextFormat,
     },
     diagnostics::Severity,
-    display::{file_structure, FunctionSignature, NavigationTarget, StructureNode},
+    display::{file_structure, FunctionSignature, NavSpec, NavigationTarget, StructureNode},
     expand_macro::ExpandedMacro,
     folding_ranges::{Fold, FoldKind},
     hover::HoverResult,
     inlay_hints::{InlayHint, InlayHintsConfig, InlayKind},
-    references::{Declaration, Reference, ReferenceAccess, ReferenceKind, ReferenceSearchResult},
+    references::{
+        Declaration, DeclarationKind, Definition, EnclosingItem, FindAllRefsConfig, Reference,
+        ReferenceAccess, ReferenceKind, ReferenceSearchResult, RenameError,
+    },
     runnables::{Runnable, RunnableKind, TestId},
     ssr::SsrError,
     syntax_highlighting::{
@@ -388,9 +391,17 @@ impl Analysis {
     pub fn find_all_refs(
         &self,
         position: FilePosition,
-        search_scope: Option<SearchScope>,
+        config: &FindAllRefsConfig,
     ) -> Cancelable<Option<ReferenceSearchResult>> {
-        self.with_db(|db| references::find_all_refs(db, position, search_scope).map(|it| it.info))
+        self.with_db(|db| references::find_all_refs(db, position, config).map(|it| it.info))
+    }
+
+    /// Finds all usages of every definition which is named inside the selection.
+    pub fn find_all_refs_in_selection(
+        &self,
+        frange: FileRange,
+    ) -> Cancelable<Vec<RangeInfo<ReferenceSearchResult>>> {
+        self.with_db(|db| references::find_all_refs_in_selection(db, frange))
     }
 
     /// Returns a short text describing element at position.
@@ -491,13 +502,18 @@ impl Analysis {
         self.with_db(|db| diagnostics::diagnostics(db, file_id))
     }
 
+    /// Returns the range of the name which would be renamed at `position`.
+    pub fn prepare_rename(&self, position: FilePosition) -> Cancelable<Option<TextRange>> {
+        self.with_db(|db| references::prepare_rename(db, position))
+    }
+
     /// Returns the edit required to rename reference at the position to the new
     /// name.
     pub fn rename(
         &self,
         position: FilePosition,
         new_name: &str,
-    ) -> Cancelable<Option<RangeInfo<SourceChange>>> {
+    ) -> Cancelable<Result<Option<RangeInfo<SourceChange>>, RangeInfo<RenameError>>> {
         self.with_db(|db| references::rename(db, position, new_name))
     }
 
@@ -529,10 +545,15 @@ fn analysis_is_send() {
 
 #[cfg(test)]
 mod tests {
-    use crate::{display::NavigationTarget, mock_analysis::single_file, Query};
+    use crate::{
+        display::{NavSpec, NavigationTarget},
+        mock_analysis::single_file,
+        Query,
+    };
     use ra_syntax::{
         SmolStr,
         SyntaxKind::{FN_DEF, STRUCT_DEF},
+        TextRange,
     };
 
     #[test]
@@ -595,6 +616,28 @@ struct Foo;
         assert_eq!(struct_match, Some(STRUCT_DEF));
     }
 
+    #[test]
+    fn test_nav_matches_spec() {
+        let code = r#"
+fn foo() {}
+        "#;
+
+        let (analysis, file_id) = single_file(code);
+        let symbols = analysis.symbol_search(Query::new("foo".into())).unwrap();
+        let nav = &symbols[0];
+
+        let spec = NavSpec {
+            name: "foo".into(),
+            kind: FN_DEF,
+            file_id,
+            full_range: TextRange::new(1.into(), 12.into()),
+            focus_range: Some(TextRange::new(4.into(), 7.into())),
+        };
+        assert!(nav.matches_spec(&spec));
+        assert!(!nav.matches_spec(&NavSpec { kind: STRUCT_DEF, ..spec.clone() }));
+        assert!(!nav.matches_spec(&NavSpec { focus_range: None, ..spec }));
+    }
+
     fn get_symbols_matching(text: &str, query: &str) -> Vec<NavigationTarget> {
         let (analysis, _) = single_file(text);
         analysis.symbol_search(Query::new(query.into())).unwrap()
@@ -5,7 +5,10 @@ use std::sync::Arc;
 
 use ra_cfg::CfgOptions;
 use ra_db::{CrateName, Env, RelativePathBuf};
-use test_utils::{extract_offset, extract_range, parse_fixture, FixtureEntry, CURSOR_MARKER};
+use test_utils::{
+    extract_named_offsets, extract_offset, extract_range, parse_fixture, FixtureEntry,
+    CURSOR_MARKER, CURSOR_MARKER_DOLLAR,
+};
 
 use crate::{
     Analysis, AnalysisChange, AnalysisHost, CrateGraph, Edition, FileId, FilePosition, FileRange,
@@ -220,6 +223,35 @@ pub fn analysis_and_position(ra_fixture: &str) -> (Analysis, FilePosition) {
     (mock.analysis(), position)
 }
 
+/// Creates analysis from a multi-file fixture, returns position marked with
+/// `<|name|>`, or with `<|>` or `$0` if `marker_name` is `None`. All other
+/// named markers are removed from the fixture.
+pub fn analysis_at_marker(ra_fixture: &str, marker_name: Option<&str>) -> (Analysis, FilePosition) {
+    let marker = match marker_name {
+        Some(name) => format!("<|{}|>", name),
+        None => format!("{} or {}", CURSOR_MARKER, CURSOR_MARKER_DOLLAR),
+    };
+    let mut position = None;
+    let mut mock = MockAnalysis::new();
+    for mut entry in parse_fixture(ra_fixture) {
+        let (offsets, mut text) = extract_named_offsets(&entry.text);
+        let mut offset = offsets.get(marker_name.unwrap_or("")).copied();
+        if marker_name.is_none() && offset.is_none() && text.contains(CURSOR_MARKER_DOLLAR) {
+            let (dollar_offset, dollar_text) = extract_offset(&text);
+            offset = Some(dollar_offset);
+            text = dollar_text;
+        }
+        entry.text = text;
+        let file_id = mock.add_file_fixture(entry);
+        if let Some(offset) = offset {
+            assert!(position.is_none(), "only one {} marker per fixture is allowed", marker);
+            position = Some(FilePosition { file_id, offset });
+        }
+    }
+    let position = position.unwrap_or_else(|| panic!("expected a marker ({})", marker));
+    (mock.analysis(), position)
+}
+
 /// Creates analysis for a single file.
 pub fn single_file(ra_fixture: &str) -> (Analysis, FileId) {
     let mut mock = MockAnalysis::new();
@@ -240,3 +272,46 @@ pub fn single_file_with_range(ra_fixture: &str) -> (Analysis, FileRange) {
     let pos = mock.add_file_with_range("/main.rs", ra_fixture);
     (mock.analysis(), pos)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Edition, TextSize};
+
+    use super::{analysis_at_marker, MockAnalysis};
+
+    #[test]
+    fn fixture_sets_crate_edition() {
+        let mock = MockAnalysis::with_files(
+            r#"
+            //- /lib.rs edition:2021
+            fn foo() {}
+            "#,
+        );
+        let file_id = mock.id_of("/lib.rs");
+        let analysis = mock.analysis();
+        let krate = analysis.crate_for(file_id).unwrap()[0];
+        assert_eq!(analysis.crate_edition(krate).unwrap(), Edition::Edition2021);
+    }
+
+    #[test]
+    fn analysis_at_marker_accepts_dollar_and_strips_named_markers() {
+        let fixture = r#"
+            //- /lib.rs
+            fn <|def|>foo() {}
+            fn bar() { foo$0(); }
+            "#;
+        let (analysis, position) = analysis_at_marker(fixture, None);
+        assert_eq!(
+            analysis.file_text(position.file_id).unwrap().as_str(),
+            "fn foo() {}\nfn bar() { foo(); }\n"
+        );
+        assert_eq!(position.offset, TextSize::from(26));
+
+        let (analysis, position) = analysis_at_marker(fixture, Some("def"));
+        assert_eq!(
+            analysis.file_text(position.file_id).unwrap().as_str(),
+            "fn foo() {}\nfn bar() { foo$0(); }\n"
+        );
+        assert_eq!(position.offset, TextSize::from(3));
+    }
+}
@@ -11,39 +11,101 @@
 
 mod rename;
 
-use hir::Semantics;
+use hir::{Adt, ModuleDef, PathResolution, Semantics, Visibility};
+use ra_db::SourceDatabaseExt;
 use ra_ide_db::{
-    defs::{classify_name, classify_name_ref, Definition},
-    search::SearchScope,
-    RootDatabase,
+    defs::{classify_name, classify_name_ref},
+    search::{self, SearchScope},
+    LineIndexDatabase, RootDatabase,
 };
 use ra_prof::profile;
 use ra_syntax::{
     algo::find_node_at_offset,
     ast::{self, NameOwner},
-    AstNode, SyntaxKind, SyntaxNode, TextRange, TokenAtOffset,
+    match_ast, AstNode, SyntaxKind, SyntaxNode, SyntaxToken, TextRange, TokenAtOffset, T,
 };
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{display::TryToNav, FilePosition, FileRange, NavigationTarget, RangeInfo};
 
-pub(crate) use self::rename::rename;
+pub use self::rename::RenameError;
+pub(crate) use self::rename::{prepare_rename, rename};
 
-pub use ra_ide_db::search::{Reference, ReferenceAccess, ReferenceKind};
+pub use ra_ide_db::{
+    defs::Definition,
+    search::{EnclosingItem, Reference, ReferenceAccess, ReferenceKind},
+};
 
 #[derive(Debug, Clone)]
 pub struct ReferenceSearchResult {
+    definition: Option<Definition>,
     declaration: Declaration,
     references: Vec<Reference>,
+    include_declaration: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Declaration {
     pub nav: NavigationTarget,
     pub kind: ReferenceKind,
+    pub def_kind: DeclarationKind,
     pub access: Option<ReferenceAccess>,
+    pub visibility: Option<Visibility>,
+}
+
+/// What sort of item is being declared, mirroring `Definition`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DeclarationKind {
+    Macro,
+    Field,
+    Module,
+    Function,
+    Struct,
+    Union,
+    Enum,
+    EnumVariant,
+    Const,
+    Static,
+    Trait,
+    TypeAlias,
+    BuiltinType,
+    SelfType,
+    Local,
+    TypeParam,
+    LifetimeParam,
+}
+
+impl From<&Definition> for DeclarationKind {
+    fn from(def: &Definition) -> DeclarationKind {
+        match def {
+            Definition::Macro(_) => DeclarationKind::Macro,
+            Definition::Field(_) => DeclarationKind::Field,
+            Definition::ModuleDef(def) => match def {
+                ModuleDef::Module(_) => DeclarationKind::Module,
+                ModuleDef::Function(_) => DeclarationKind::Function,
+                ModuleDef::Adt(Adt::Struct(_)) => DeclarationKind::Struct,
+                ModuleDef::Adt(Adt::Union(_)) => DeclarationKind::Union,
+                ModuleDef::Adt(Adt::Enum(_)) => DeclarationKind::Enum,
+                ModuleDef::EnumVariant(_) => DeclarationKind::EnumVariant,
+                ModuleDef::Const(_) => DeclarationKind::Const,
+                ModuleDef::Static(_) => DeclarationKind::Static,
+                ModuleDef::Trait(_) => DeclarationKind::Trait,
+                ModuleDef::TypeAlias(_) => DeclarationKind::TypeAlias,
+                ModuleDef::BuiltinType(_) => DeclarationKind::BuiltinType,
+            },
+            Definition::SelfType(_) => DeclarationKind::SelfType,
+            Definition::Local(_) => DeclarationKind::Local,
+            Definition::TypeParam(_) => DeclarationKind::TypeParam,
+        }
+    }
 }
 
 impl ReferenceSearchResult {
+    /// The resolved definition, `None` for lifetimes, which aren't `Definition`s.
+    pub fn definition(&self) -> Option<Definition> {
+        self.definition
+    }
+
     pub fn declaration(&self) -> &Declaration {
         &self.declaration
     }
@@ -58,9 +120,75 @@ impl ReferenceSearchResult {
 
     /// Total number of references
     /// At least 1 since all valid references should
-    /// Have a declaration
+    /// Have a declaration, unless `references_only` was used
     pub fn len(&self) -> usize {
-        self.references.len() + 1
+        self.references.len() + self.include_declaration as usize
+    }
+
+    /// Number of references of each kind, counting the declaration like `len`.
+    pub fn kind_counts(&self) -> FxHashMap<ReferenceKind, usize> {
+        let mut res = FxHashMap::default();
+        for kind in self.kinds_and_accesses().map(|(kind, _)| kind) {
+            *res.entry(kind.clone()).or_insert(0) += 1;
+        }
+        res
+    }
+
+    /// Number of reads and writes, counting the declaration like `len`.
+    /// References without an access are skipped.
+    pub fn access_counts(&self) -> FxHashMap<ReferenceAccess, usize> {
+        let mut res = FxHashMap::default();
+        for access in self.kinds_and_accesses().filter_map(|(_, access)| access) {
+            *res.entry(access).or_insert(0) += 1;
+        }
+        res
+    }
+
+    fn kinds_and_accesses(
+        &self,
+    ) -> impl Iterator<Item = (&ReferenceKind, Option<ReferenceAccess>)> + '_ {
+        let declaration = if self.include_declaration { Some(&self.declaration) } else { None };
+        declaration
+            .map(|decl| (&decl.kind, decl.access))
+            .into_iter()
+            .chain(self.references.iter().map(|r| (&r.kind, r.access)))
+    }
+
+    /// Excludes the declaration from `len`, iteration and
+    /// `to_lsp_locations`. It is still available via `declaration`.
+    pub fn references_only(mut self) -> ReferenceSearchResult {
+        self.include_declaration = false;
+        self
+    }
+
+    /// Renders the declaration and all references as a JSON array of LSP
+    /// `Location`s, with `file:///` URIs built from the relative file paths.
+    pub fn to_lsp_locations(&self, db: &RootDatabase) -> serde_json::Value {
+        let location = |file_range: FileRange| {
+            let line_index = db.line_index(file_range.file_id);
+            let position = |offset| {
+                let line_col = line_index.line_col(offset);
+                serde_json::json!({ "line": line_col.line, "character": line_col.col_utf16 })
+            };
+            serde_json::json!({
+                "uri": format!("file:///{}", db.file_relative_path(file_range.file_id)),
+                "range": {
+                    "start": position(file_range.range.start()),
+                    "end": position(file_range.range.end()),
+                },
+            })
+        };
+        let declaration = FileRange {
+            file_id: self.declaration.nav.file_id(),
+            range: self.declaration.nav.range(),
+        };
+        let locations = Some(declaration)
+            .filter(|_| self.include_declaration)
+            .into_iter()
+            .chain(self.references.iter().map(|reference| reference.file_range))
+            .map(location)
+            .collect();
+        serde_json::Value::Array(locations)
     }
 }
 
@@ -72,56 +200,197 @@ impl IntoIterator for ReferenceSearchResult {
 
     fn into_iter(mut self) -> Self::IntoIter {
         let mut v = Vec::with_capacity(self.len());
-        v.push(Reference {
-            file_range: FileRange {
-                file_id: self.declaration.nav.file_id(),
-                range: self.declaration.nav.range(),
-            },
-            kind: self.declaration.kind,
-            access: self.declaration.access,
-        });
+        if self.include_declaration {
+            v.push(Reference {
+                file_range: FileRange {
+                    file_id: self.declaration.nav.file_id(),
+                    range: self.declaration.nav.range(),
+                },
+                kind: self.declaration.kind,
+                access: self.declaration.access,
+                block_scope: None,
+                in_test: false,
+                enclosing: None,
+            });
+        }
         v.append(&mut self.references);
         v.into_iter()
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct FindAllRefsConfig {
+    /// Restricts the search to this scope, in addition to the scope where the
+    /// definition is visible.
+    pub search_scope: Option<SearchScope>,
+    /// Only references of these kinds are returned. The declaration is always
+    /// present.
+    pub kinds: Option<Vec<ReferenceKind>>,
+    /// Records the enclosing item of each reference in `Reference::enclosing`.
+    pub with_enclosing: bool,
+    /// Skips references inside test code, see `Reference::in_test`.
+    pub exclude_tests: bool,
+    /// When searching from a method of an `impl Trait for X` block, also
+    /// reports the declaration of the method in `Trait`.
+    pub include_trait_item: bool,
+}
+
 pub(crate) fn find_all_refs(
     db: &RootDatabase,
     position: FilePosition,
-    search_scope: Option<SearchScope>,
+    config: &FindAllRefsConfig,
 ) -> Option<RangeInfo<ReferenceSearchResult>> {
     let _p = profile("find_all_refs");
     let sema = Semantics::new(db);
     let syntax = sema.parse(position.file_id).syntax().clone();
 
-    let (opt_name, search_kind) = if let Some(name) =
-        get_struct_def_name_for_struct_literal_search(&sema, &syntax, position)
-    {
-        (Some(name), ReferenceKind::StructLiteral)
-    } else {
-        (
-            sema.find_node_at_offset_with_descend::<ast::Name>(&syntax, position.offset),
-            ReferenceKind::Other,
-        )
-    };
+    let matches_kinds =
+        |r: &Reference| config.kinds.as_ref().map_or(true, |kinds| kinds.contains(&r.kind));
+
+    if let Some(mut res) = find_lifetime_refs(&syntax, position) {
+        // The lifetime's item is always searched, but only references
+        // inside `search_scope` are kept, like for other definitions.
+        let in_scope = |r: &Reference| {
+            config.search_scope.as_ref().map_or(true, |scope| scope.contains(r.file_range))
+        };
+        res.info.references.retain(|r| matches_kinds(r) && in_scope(r));
+        if config.with_enclosing {
+            set_enclosing_items(&sema, &mut res.info.references);
+        }
+        return Some(res);
+    }
 
-    let RangeInfo { range, info: def } = find_name(&sema, &syntax, position, opt_name)?;
+    let (RangeInfo { range, info: def }, search_kind) = find_definition(&sema, &syntax, position)?;
 
-    let references = def
-        .find_usages(db, search_scope)
-        .into_iter()
-        .filter(|r| search_kind == ReferenceKind::Other || search_kind == r.kind)
-        .collect();
+    let mut references = Vec::new();
+    def.find_usages_with(db, config.search_scope.clone(), &mut |r| {
+        if (search_kind == ReferenceKind::Other || search_kind == r.kind)
+            && matches_kinds(&r)
+            && !(config.exclude_tests && r.in_test)
+        {
+            references.push(r);
+        }
+        true
+    });
+    if config.include_trait_item {
+        if let Definition::ModuleDef(ModuleDef::Function(func)) = def {
+            let trait_item = search::implemented_trait_item(db, func).filter(|r| {
+                config.search_scope.as_ref().map_or(true, |scope| scope.contains(r.file_range))
+                    && matches_kinds(r)
+                    && !(config.exclude_tests && r.in_test)
+            });
+            references.extend(trait_item);
+        }
+    }
+    references.sort_by_key(|r| (r.file_range.file_id, r.file_range.range.start()));
+    dedup_references(&mut references);
+    if config.with_enclosing {
+        set_enclosing_items(&sema, &mut references);
+    }
 
     let decl_range = def.try_to_nav(db)?.range();
 
     let declaration = Declaration {
         nav: def.try_to_nav(db)?,
         kind: ReferenceKind::Other,
+        def_kind: DeclarationKind::from(&def),
         access: decl_access(&def, &syntax, decl_range),
+        visibility: def.visibility(db),
     };
 
-    Some(RangeInfo::new(range, ReferenceSearchResult { declaration, references }))
+    Some(RangeInfo::new(
+        range,
+        ReferenceSearchResult {
+            definition: Some(def),
+            declaration,
+            references,
+            include_declaration: true,
+        },
+    ))
+}
+
+/// Removes references with the same range and kind, keeping the first one.
+/// These can show up when macro output maps back to hand-written code.
+fn dedup_references(references: &mut Vec<Reference>) {
+    let mut seen = FxHashSet::default();
+    references.retain(|r| seen.insert((r.file_range.file_id, r.file_range.range, r.kind.clone())));
+}
+
+fn set_enclosing_items(sema: &Semantics<RootDatabase>, references: &mut [Reference]) {
+    for reference in references {
+        let syntax = sema.parse(reference.file_range.file_id).syntax().clone();
+        reference.enclosing = enclosing_item(&syntax, reference.file_range.range);
+    }
+}
+
+fn enclosing_item(syntax: &SyntaxNode, range: TextRange) -> Option<EnclosingItem> {
+    let token = syntax.token_at_offset(range.start()).right_biased()?;
+    token.parent().ancestors().find_map(|node| {
+        let name = match_ast! {
+            match node {
+                ast::FnDef(it) => it.name()?.text().clone(),
+                ast::ConstDef(it) => it.name()?.text().clone(),
+                ast::StaticDef(it) => it.name()?.text().clone(),
+                ast::ImplDef(it) => it.target_type()?.syntax().text().to_string().into(),
+                _ => return None,
+            }
+        };
+        Some(EnclosingItem { name, kind: node.kind(), range: node.text_range() })
+    })
+}
+
+pub(crate) fn find_all_refs_in_selection(
+    db: &RootDatabase,
+    frange: FileRange,
+) -> Vec<RangeInfo<ReferenceSearchResult>> {
+    let _p = profile("find_all_refs_in_selection");
+    let sema = Semantics::new(db);
+    let syntax = sema.parse(frange.file_id).syntax().clone();
+
+    let mut seen: Vec<Definition> = Vec::new();
+    let mut res = Vec::new();
+    for node in syntax.descendants() {
+        if !frange.range.contains_range(node.text_range()) {
+            continue;
+        }
+        let def = match_ast! {
+            match node {
+                ast::Name(name) => classify_name(&sema, &name).map(|it| it.definition()),
+                ast::NameRef(name_ref) => {
+                    classify_name_ref(&sema, &name_ref).map(|it| it.definition())
+                },
+                _ => None,
+            }
+        };
+        let def = match def {
+            Some(it) if !seen.contains(&it) => it,
+            _ => continue,
+        };
+        seen.push(def);
+
+        let position = FilePosition { file_id: frange.file_id, offset: node.text_range().start() };
+        res.extend(find_all_refs(db, position, &FindAllRefsConfig::default()));
+    }
+    res
+}
+
+/// Finds the definition named at `position`, along with the kind of references
+/// to look for: on `Foo {` only struct literals are searched.
+fn find_definition(
+    sema: &Semantics<RootDatabase>,
+    syntax: &SyntaxNode,
+    position: FilePosition,
+) -> Option<(RangeInfo<Definition>, ReferenceKind)> {
+    let (opt_name, search_kind) =
+        if let Some(name) = get_struct_def_name_for_struct_literal_search(sema, syntax, position) {
+            (Some(name), ReferenceKind::StructLiteral)
+        } else {
+            (
+                sema.find_node_at_offset_with_descend::<ast::Name>(syntax, position.offset),
+                ReferenceKind::Other,
+            )
+        };
+    Some((find_name(sema, syntax, position, opt_name)?, search_kind))
 }
 
 fn find_name(
@@ -135,13 +404,105 @@ fn find_name(
         let range = name.syntax().text_range();
         return Some(RangeInfo::new(range, def));
     }
+    if let Some(res) = find_self_param(sema, syntax, position) {
+        return Some(res);
+    }
     let name_ref =
         sema.find_node_at_offset_with_descend::<ast::NameRef>(&syntax, position.offset)?;
-    let def = classify_name_ref(sema, &name_ref)?.definition();
+    let def = match classify_name_ref(sema, &name_ref)?.definition() {
+        // `Self` is searched for as the type it stands for.
+        Definition::SelfType(impl_def) => {
+            let adt = impl_def.target_ty(sema.db).as_adt()?;
+            Definition::ModuleDef(ModuleDef::Adt(adt))
+        }
+        def => def,
+    };
     let range = name_ref.syntax().text_range();
     Some(RangeInfo::new(range, def))
 }
 
+/// `self` is a keyword rather than a `Name` or `NameRef`, so the `self`
+/// parameter is resolved from the token directly.
+fn find_self_param(
+    sema: &Semantics<RootDatabase>,
+    syntax: &SyntaxNode,
+    position: FilePosition,
+) -> Option<RangeInfo<Definition>> {
+    let token = syntax.token_at_offset(position.offset).find(|it| it.kind() == T![self])?;
+    let local = match ast::SelfParam::cast(token.parent()) {
+        Some(self_param) => sema.to_def(&self_param)?,
+        None => {
+            let path = token.parent().parent().and_then(ast::Path::cast)?;
+            match sema.resolve_path(&path)? {
+                PathResolution::Local(local) => local,
+                _ => return None,
+            }
+        }
+    };
+    Some(RangeInfo::new(token.text_range(), Definition::Local(local)))
+}
+
+/// Lifetimes are not part of `Definition`, so their references are found
+/// syntactically, inside the item which declares the lifetime.
+fn find_lifetime_refs(
+    syntax: &SyntaxNode,
+    position: FilePosition,
+) -> Option<RangeInfo<ReferenceSearchResult>> {
+    let lifetime =
+        syntax.token_at_offset(position.offset).find(|it| it.kind() == SyntaxKind::LIFETIME)?;
+    let param = lifetime_param(&lifetime)?;
+    let decl_token = param.lifetime_token()?;
+    let scope = param.syntax().parent()?.parent()?;
+
+    let references = scope
+        .descendants_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|token| {
+            token.kind() == SyntaxKind::LIFETIME
+                && token.text() == lifetime.text()
+                && *token != decl_token
+                && lifetime_param(token).as_ref() == Some(&param)
+        })
+        .map(|token| Reference {
+            file_range: FileRange { file_id: position.file_id, range: token.text_range() },
+            kind: ReferenceKind::Other,
+            access: None,
+            block_scope: None,
+            in_test: false,
+            enclosing: None,
+        })
+        .collect();
+
+    let declaration = Declaration {
+        nav: NavigationTarget::from_lifetime_param(position.file_id, &param)?,
+        kind: ReferenceKind::Other,
+        def_kind: DeclarationKind::LifetimeParam,
+        access: None,
+        visibility: None,
+    };
+
+    Some(RangeInfo::new(
+        lifetime.text_range(),
+        ReferenceSearchResult {
+            definition: None,
+            declaration,
+            references,
+            include_declaration: true,
+        },
+    ))
+}
+
+/// Finds the parameter which introduces `lifetime`, looking at the generic
+/// parameters of the innermost enclosing items first.
+fn lifetime_param(lifetime: &SyntaxToken) -> Option<ast::LifetimeParam> {
+    lifetime
+        .parent()
+        .ancestors()
+        .filter_map(|node| node.children().find_map(ast::TypeParamList::cast))
+        .flat_map(|params| params.lifetime_params())
+        .find(|param| param.lifetime_token().map_or(false, |it| it.text() == lifetime.text()))
+}
+
 fn decl_access(def: &Definition, syntax: &SyntaxNode, range: TextRange) -> Option<ReferenceAccess> {
     match def {
         Definition::Local(_) | Definition::Field(_) => {}
@@ -190,9 +551,18 @@ fn get_struct_def_name_for_struct_literal_search(
 
 #[cfg(test)]
 mod tests {
+    use hir::{Adt, Module, ModuleDef, Semantics, Visibility};
+    use ra_ide_db::RootDatabase;
+    use ra_syntax::{SyntaxKind, TextRange, TextSize};
+    use test_utils::find_mismatch;
+
     use crate::{
-        mock_analysis::{analysis_and_position, single_file_with_position, MockAnalysis},
-        Declaration, Reference, ReferenceSearchResult, SearchScope,
+        mock_analysis::{
+            analysis_and_position, analysis_at_marker, single_file_with_position,
+            single_file_with_range, MockAnalysis,
+        },
+        Analysis, Declaration, DeclarationKind, Definition, FileRange, FindAllRefsConfig,
+        Reference, ReferenceAccess, ReferenceKind, ReferenceSearchResult, SearchScope,
     };
 
     #[test]
@@ -209,14 +579,34 @@ mod tests {
         f = Foo {a: Foo::f()};
     }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "Foo STRUCT_DEF FileId(1) 5..39 12..15 Other",
             &["FileId(1) 138..141 StructLiteral"],
         );
     }
 
+    #[test]
+    fn test_struct_literal_references_only() {
+        let code = r#"
+    struct Foo <|>{}
+    fn main() {
+        let f: Foo;
+        f = Foo {};
+    }"#;
+
+        let (_, refs) = get_all_refs(code);
+        assert_eq!(refs.len(), 2);
+
+        let refs = refs.references_only();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs.declaration().nav.name(), "Foo");
+        let kinds: Vec<_> = refs.into_iter().map(|r| r.kind).collect();
+        assert_eq!(kinds, vec![ReferenceKind::StructLiteral]);
+    }
+
     #[test]
     fn test_struct_literal_befor_space() {
         let code = r#"
@@ -226,8 +616,9 @@ mod tests {
         f = Foo {};
     }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "Foo STRUCT_DEF FileId(1) 5..18 12..15 Other",
             &["FileId(1) 54..57 Other", "FileId(1) 71..74 StructLiteral"],
@@ -243,8 +634,9 @@ mod tests {
         f = Foo {};
     }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "Foo STRUCT_DEF FileId(1) 5..21 12..15 Other",
             &["FileId(1) 81..84 StructLiteral"],
@@ -261,8 +653,9 @@ mod tests {
         f = Foo(1);
     }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "Foo STRUCT_DEF FileId(1) 5..21 12..15 Other",
             &["FileId(1) 71..74 StructLiteral"],
@@ -284,8 +677,9 @@ mod tests {
         i = 5;
     }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "i BIND_PAT FileId(1) 33..34 Other Write",
             &[
@@ -297,6 +691,117 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_local_search_scope_is_owning_body() {
+        for code in &[
+            "fn foo() { let x<|> = 1; }\nfn bar() {}",
+            "const C: u32 = { let x<|> = 1; (|| x)() };\nfn bar() {}",
+            "static S: u32 = { let x<|> = 1; x };\nfn bar() {}",
+        ] {
+            let (mock, pos) =
+                MockAnalysis::with_files_and_position(&format!("//- /main.rs\n{}", code));
+            let host = mock.analysis_host();
+            let refs =
+                host.analysis().find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+            let scope = refs.definition().unwrap().search_scope(host.raw_database());
+
+            let text = code.replace("<|>", "");
+            let range_of = |needle: &str| FileRange {
+                file_id: pos.file_id,
+                range: TextRange::at(
+                    (text.find(needle).unwrap() as u32).into(),
+                    TextSize::of(needle),
+                ),
+            };
+            assert!(scope.contains(range_of("x")), "{}", code);
+            // Neither the item's signature nor other items are searched.
+            assert!(!scope.contains(range_of(&text[..2])), "{}", code);
+            assert!(!scope.contains(range_of("bar")), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_find_all_refs_for_param_stays_in_body() {
+        let code = r#"
+    fn foo(spam<|>: u32) -> u32 {
+        {
+            let spam = spam + 1;
+            spam
+        }
+    }
+
+    fn bar(spam: u32) -> u32 { spam }"#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "spam BIND_PAT FileId(1) 12..16 Other",
+            &["FileId(1) 65..69 Other Read"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_for_shadowed_local() {
+        let code = r#"
+    fn main() {
+        let x<|> = 1;
+        let y = x;
+        {
+            let x = 2;
+            let z = x;
+        }
+    }"#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "x BIND_PAT FileId(1) 29..30 Other",
+            &["FileId(1) 52..53 Other Read"],
+        );
+
+        let code = r#"
+    fn main() {
+        let x = 1;
+        let y = x;
+        {
+            let x<|> = 2;
+            let z = x;
+        }
+    }"#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "x BIND_PAT FileId(1) 81..82 Other",
+            &["FileId(1) 108..109 Other Read"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_for_local_reports_block_scope() {
+        let code = r#"
+    fn main() {
+        let x<|> = 1;
+        {
+            x;
+        }
+        x;
+    }"#;
+
+        let (_, refs) = get_all_refs(code);
+        let scopes: Vec<_> = refs.references().iter().map(|r| r.block_scope).collect();
+        assert_eq!(
+            scopes,
+            vec![
+                Some(TextRange::new(44.into(), 70.into())),
+                Some(TextRange::new(15.into(), 87.into())),
+            ]
+        );
+    }
+
     #[test]
     fn search_filters_by_range() {
         let code = r#"
@@ -309,14 +814,87 @@ mod tests {
                 spam + spam
             }
         "#;
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "spam BIND_PAT FileId(1) 44..48 Other",
             &["FileId(1) 71..75 Other Read", "FileId(1) 78..82 Other Read"],
         );
     }
 
+    #[test]
+    fn search_filters_by_kind() {
+        let code = r#"
+            struct Foo<|> {}
+            fn foo(f: Foo) -> Foo {
+                let Foo {} = f;
+                Foo {}
+            }
+        "#;
+        let (analysis, pos) = single_file_with_position(code);
+        let kinds = |kinds| FindAllRefsConfig { kinds: Some(kinds), ..Default::default() };
+        let refs = analysis
+            .find_all_refs(pos, &kinds(vec![ReferenceKind::StructLiteral]))
+            .unwrap()
+            .unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "Foo STRUCT_DEF FileId(1) 13..26 20..23 Other",
+            &["FileId(1) 111..114 StructLiteral"],
+        );
+
+        let refs = analysis
+            .find_all_refs(pos, &kinds(vec![ReferenceKind::Other, ReferenceKind::Pattern]))
+            .unwrap()
+            .unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "Foo STRUCT_DEF FileId(1) 13..26 20..23 Other",
+            &["FileId(1) 49..52 Other", "FileId(1) 57..60 Other", "FileId(1) 83..86 Pattern"],
+        );
+
+        let refs =
+            analysis.find_all_refs(pos, &kinds(vec![ReferenceKind::Conversion])).unwrap().unwrap();
+        check_result(&analysis, refs, "Foo STRUCT_DEF FileId(1) 13..26 20..23 Other", &[]);
+    }
+
+    #[test]
+    fn test_find_all_refs_self_param() {
+        let code = r#"
+            struct Foo { x: i32 }
+            impl Foo {
+                fn inc(&mut self) { self.x += 1; }
+                fn reset(&mut se<|>lf) {
+                    self.x = 0;
+                    self.inc();
+                    let _ = self;
+                }
+            }
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "self BIND_PAT FileId(1) 134..143 Other",
+            &[
+                "FileId(1) 167..171 Other Read",
+                "FileId(1) 199..203 Other Write",
+                "FileId(1) 239..243 Other Read",
+            ],
+        );
+
+        let (analysis, pos) = single_file_with_position(
+            &code.replace("se<|>lf", "self").replace("self.inc", "<|>self.inc"),
+        );
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        assert_eq!(refs.declaration().nav.range(), TextRange::new(134.into(), 143.into()));
+        assert_eq!(refs.references().len(), 3);
+    }
+
     #[test]
     fn test_find_all_refs_for_param_inside() {
         let code = r#"
@@ -324,8 +902,13 @@ mod tests {
         i<|>
     }"#;
 
-        let refs = get_all_refs(code);
-        check_result(refs, "i BIND_PAT FileId(1) 12..13 Other", &["FileId(1) 38..39 Other Read"]);
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "i BIND_PAT FileId(1) 12..13 Other",
+            &["FileId(1) 38..39 Other Read"],
+        );
     }
 
     #[test]
@@ -335,8 +918,13 @@ mod tests {
         i
     }"#;
 
-        let refs = get_all_refs(code);
-        check_result(refs, "i BIND_PAT FileId(1) 12..13 Other", &["FileId(1) 38..39 Other Read"]);
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "i BIND_PAT FileId(1) 12..13 Other",
+            &["FileId(1) 38..39 Other Read"],
+        );
     }
 
     #[test]
@@ -352,14 +940,61 @@ mod tests {
             }
         "#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
-            "spam RECORD_FIELD_DEF FileId(1) 66..79 70..74 Other",
+            "spam RECORD_FIELD_DEF FileId(1) 66..79 70..74 Other pub",
             &["FileId(1) 152..156 Other Read"],
         );
     }
 
+    #[test]
+    fn test_find_all_refs_field_name_in_record_pat() {
+        let code = r#"
+            struct Foo {
+                spam<|>: u32,
+            }
+
+            fn main(s: Foo) {
+                let Foo { spam, .. } = s;
+                let f = s.spam;
+            }
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "spam RECORD_FIELD_DEF FileId(1) 42..51 42..46 Other",
+            &["FileId(1) 124..128 FieldShorthandForField Read", "FileId(1) 166..170 Other Read"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_field_shorthand_in_record_lit() {
+        let code = r#"
+            struct Foo {
+                x<|>: i32,
+                y: i32,
+            }
+
+            fn main() {
+                let x = 5;
+                let f = Foo { x, y: 1 };
+                let g = Foo { x: 2, y: x };
+            }
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "x RECORD_FIELD_DEF FileId(1) 42..48 42..43 Other",
+            &["FileId(1) 170..171 FieldShorthandForField Read", "FileId(1) 211..212 Other Read"],
+        );
+    }
+
     #[test]
     fn test_find_all_refs_impl_item_name() {
         let code = r#"
@@ -370,8 +1005,8 @@ mod tests {
             }
         "#;
 
-        let refs = get_all_refs(code);
-        check_result(refs, "f FN_DEF FileId(1) 88..104 91..92 Other", &[]);
+        let (analysis, refs) = get_all_refs(code);
+        check_result(&analysis, refs, "f FN_DEF FileId(1) 88..104 91..92 Other", &[]);
     }
 
     #[test]
@@ -385,56 +1020,656 @@ mod tests {
             }
         "#;
 
-        let refs = get_all_refs(code);
-        check_result(refs, "B ENUM_VARIANT FileId(1) 83..84 83..84 Other", &[]);
+        let (analysis, refs) = get_all_refs(code);
+        check_result(&analysis, refs, "B ENUM_VARIANT FileId(1) 83..84 83..84 Other", &[]);
     }
 
     #[test]
-    fn test_find_all_refs_two_modules() {
+    fn test_find_all_refs_enum_var_in_patterns() {
         let code = r#"
-            //- /lib.rs
-            pub mod foo;
-            pub mod bar;
+            enum Foo {
+                A,
+                B<|>(i32),
+            }
 
-            fn f() {
-                let i = foo::Foo { n: 5 };
+            fn main() {
+                let x = Foo::B(92);
+                match x {
+                    Foo::A | Foo::B(0) => (),
+                    Foo::B(_) => (),
+                }
+                if let Foo::B(n) = x {}
+                while let Foo::B(..) = x {}
             }
+        "#;
 
-            //- /foo.rs
-            use crate::bar;
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "B ENUM_VARIANT FileId(1) 59..65 59..60 Other",
+            &[
+                "FileId(1) 135..136 StructLiteral",
+                "FileId(1) 202..203 Pattern",
+                "FileId(1) 239..240 Pattern",
+                "FileId(1) 297..298 Pattern",
+                "FileId(1) 340..341 Pattern",
+            ],
+        );
+    }
 
-            pub struct Foo {
-                pub n: u32,
+    #[test]
+    fn test_find_all_refs_in_macro_call() {
+        let code = r#"
+            macro_rules! m1 {
+                ($e:expr) => { let _ = $e; };
             }
 
-            fn f() {
-                let i = bar::Bar { n: 5 };
+            fn foo<|>() {}
+
+            fn main() {
+                m1!(foo);
             }
+        "#;
 
-            //- /bar.rs
-            use crate::foo;
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "foo FN_DEF FileId(1) 104..115 107..110 Other",
+            &["FileId(1) 161..164 Other"],
+        );
+    }
 
-            pub struct Bar {
-                pub n: u32,
+    #[test]
+    fn test_find_all_refs_enum_var_in_macro_pattern() {
+        let code = r#"
+            macro_rules! matches {
+                ($expression:expr, $pattern:pat) => {
+                    match $expression {
+                        $pattern => true,
+                        _ => false,
+                    }
+                };
             }
 
-            fn f() {
-                let i = foo::Foo<|> { n: 5 };
+            enum Foo {
+                A,
+                B<|>,
+            }
+
+            fn main() {
+                let f = Foo::A;
+                matches!(f, Foo::B);
             }
         "#;
 
-        let (analysis, pos) = analysis_and_position(code);
-        let refs = analysis.find_all_refs(pos, None).unwrap().unwrap();
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
-            "Foo STRUCT_DEF FileId(2) 16..50 27..30 Other",
-            &["FileId(1) 52..55 StructLiteral", "FileId(3) 77..80 StructLiteral"],
+            "B ENUM_VARIANT FileId(1) 322..323 322..323 Other",
+            &["FileId(1) 429..430 Pattern"],
         );
     }
 
-    // `mod foo;` is not in the results because `foo` is an `ast::Name`.
-    // So, there are two references: the first one is a definition of the `foo` module,
-    // which is the whole `foo.rs`, and the second one is in `use foo::Foo`.
+    #[test]
+    fn test_find_all_refs_assoc_type_projection() {
+        let code = r#"
+            trait Trait {
+                type Assoc<|>;
+            }
+
+            fn foo<T: Trait>(t: <T as Trait>::Assoc) {}
+            fn bar<T: Trait>() -> <T as Trait>::Assoc { loop {} }
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "Assoc TYPE_ALIAS_DEF FileId(1) 43..54 48..53 Other",
+            &["FileId(1) 116..121 Other", "FileId(1) 174..179 Other"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_in_derive_helper_attr() {
+        let code = r#"
+            #[derive(Foo)]
+            #[foo(with = crate::helper)]
+            struct S;
+
+            fn helper<|>() {}
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "helper FN_DEF FileId(1) 104..118 107..113 Other",
+            &["FileId(1) 60..66 Attribute"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_reports_test_usages() {
+        let code = r#"
+            fn foo<|>() {}
+
+            fn main() { foo(); }
+
+            #[cfg(test)]
+            mod tests {
+                fn check() { super::foo(); }
+            }
+        "#;
+
+        let (_, refs) = get_all_refs(code);
+        let in_test: Vec<_> = refs.references().iter().map(|r| r.in_test).collect();
+        assert_eq!(in_test, vec![false, true]);
+    }
+
+    #[test]
+    fn test_find_all_refs_exclude_tests() {
+        let code = r#"
+            fn foo<|>() {}
+
+            fn main() { foo(); }
+
+            #[test]
+            fn check_foo() { foo(); }
+
+            #[cfg(test)]
+            mod unit {
+                fn check() { super::foo(); }
+            }
+        "#;
+
+        let (analysis, pos) = single_file_with_position(code);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        let in_test: Vec<_> = refs.references().iter().map(|r| r.in_test).collect();
+        assert_eq!(in_test, vec![false, true, true]);
+
+        let config = FindAllRefsConfig { exclude_tests: true, ..FindAllRefsConfig::default() };
+        let refs = analysis.find_all_refs(pos, &config).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "foo FN_DEF FileId(1) 13..24 16..19 Other",
+            &["FileId(1) 50..53 StructLiteral"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_reports_usages_under_test_cfgs() {
+        let code = r#"
+            fn foo<|>() {}
+
+            #[cfg(all(test, unix))]
+            fn a() { foo(); }
+
+            #[cfg( test )]
+            fn b() { foo(); }
+
+            #[cfg(not(test))]
+            fn c() { foo(); }
+
+            mod tests {
+                fn d() { super::foo(); }
+            }
+        "#;
+
+        let (_, refs) = get_all_refs(code);
+        let in_test: Vec<_> = refs.references().iter().map(|r| r.in_test).collect();
+        assert_eq!(in_test, vec![true, true, false, false]);
+    }
+
+    #[test]
+    fn test_find_all_refs_from_impl_finds_into_calls() {
+        let code = r#"
+            //- /lib.rs
+            use core::convert::{From, Into};
+
+            struct A;
+            struct B;
+            impl From<A> for B {
+                fn from<|>(a: A) -> B { B }
+            }
+
+            fn f() {
+                let b: B = A.into();
+            }
+
+            //- /core/lib.rs
+            pub mod convert {
+                pub trait From<T> { fn from(t: T) -> Self; }
+                pub trait Into<T> { fn into(self) -> T; }
+                impl<T, U: From<T>> Into<U> for T { fn into(self) -> U { U::from(self) } }
+            }
+        "#;
+
+        let (analysis, pos) = analysis_and_position(code);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "from FN_DEF FileId(1) 78..102 81..85 Other",
+            &["FileId(1) 131..135 Conversion"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_local_from_trait_is_not_a_conversion() {
+        let code = r#"
+            trait From<T> { fn from(t: T) -> Self; }
+            trait Into<T> { fn into(self) -> T; }
+            impl<T, U: From<T>> Into<U> for T { fn into(self) -> U { U::from(self) } }
+
+            struct A;
+            struct B;
+            impl From<A> for B {
+                fn from<|>(a: A) -> B { B }
+            }
+
+            fn f() {
+                let b: B = A.into();
+            }
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(&analysis, refs, "from FN_DEF FileId(1) 285..309 288..292 Other", &[]);
+    }
+
+    #[test]
+    fn test_find_all_refs_const_in_array_len() {
+        let code = r#"
+            const N<|>: usize = 4;
+            struct S { buf: [u8; N] }
+            fn f() -> usize {
+                let a: [u8; N] = [0; N];
+                N + 1
+            }
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "N CONST_DEF FileId(1) 13..32 19..20 Other",
+            &[
+                "FileId(1) 66..67 Other",
+                "FileId(1) 129..130 Other",
+                "FileId(1) 138..139 Other",
+                "FileId(1) 158..159 Other",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_trait_derived_impl() {
+        let code = r#"
+            //- /main.rs
+            #[derive(Clone)]
+            struct S;
+
+            //- /core/lib.rs
+            #[prelude_import]
+            use clone::*;
+            pub mod clone {
+                pub trait Clone<|> {
+                    fn clone(&self) -> Self;
+                }
+            }
+        "#;
+
+        let (analysis, pos) = analysis_and_position(code);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "Clone TRAIT_DEF FileId(2) 52..108 62..67 Other pub",
+            &["FileId(1) 0..16 Implementation"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_trait_method_impls() {
+        let code = r#"
+            //- /lib.rs
+            mod foo;
+            pub trait Greet { fn greet<|>(&self); }
+            struct A;
+            impl Greet for A { fn greet(&self) {} }
+            impl A { fn greet(&self, _: i32) {} }
+
+            //- /foo.rs
+            use crate::Greet;
+            struct B;
+            impl Greet for B { fn greet(&self) {} }
+        "#;
+
+        let (analysis, pos) = analysis_and_position(code);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        let refs: Vec<_> = refs.references().iter().map(ref_debug_render).collect();
+        assert_eq!(refs, vec!["FileId(1) 78..83 TraitImplItem", "FileId(2) 50..55 TraitImplItem"]);
+    }
+
+    #[test]
+    fn test_find_all_refs_impl_method_finds_trait_method() {
+        let code = r#"
+            //- /lib.rs
+            mod foo;
+            pub trait Greet { fn greet(&self); }
+
+            //- /foo.rs
+            use crate::Greet;
+            struct B;
+            impl Greet for B { fn greet<|>(&self) {} }
+        "#;
+
+        let (analysis, pos) = analysis_and_position(code);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        check_result(&analysis, refs, "greet FN_DEF FileId(2) 47..65 50..55 Other", &[]);
+
+        let config = FindAllRefsConfig { include_trait_item: true, ..FindAllRefsConfig::default() };
+        let refs = analysis.find_all_refs(pos, &config).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "greet FN_DEF FileId(2) 47..65 50..55 Other",
+            &["FileId(1) 30..35 TraitImplItem"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_method_through_deref() {
+        let code = r#"
+            #[lang = "deref"]
+            trait Deref {
+                type Target;
+                fn deref(&self) -> &Self::Target;
+            }
+
+            struct Inner;
+            impl Inner {
+                fn method<|>(&self) {}
+            }
+
+            struct Wrapper(Inner);
+            impl Deref for Wrapper {
+                type Target = Inner;
+                fn deref(&self) -> &Inner { &self.0 }
+            }
+
+            fn f(w: Wrapper) {
+                w.method();
+            }
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "method FN_DEF FileId(1) 218..237 221..227 Other",
+            &["FileId(1) 480..486 Other"],
+        );
+    }
+
+    #[test]
+    fn test_declaration_def_kind() {
+        let cases = [
+            ("struct Foo<|>;", DeclarationKind::Struct),
+            ("enum E { A<|> }", DeclarationKind::EnumVariant),
+            ("struct S { f<|>: u32 }", DeclarationKind::Field),
+            ("fn foo<|>() {}", DeclarationKind::Function),
+            ("fn foo<T<|>>() {}", DeclarationKind::TypeParam),
+            ("fn foo() { let x<|> = 1; }", DeclarationKind::Local),
+            ("fn foo<'a>(x: &'a<|> u32) {}", DeclarationKind::LifetimeParam),
+        ];
+        for (code, kind) in cases.iter() {
+            let (_, refs) = get_all_refs(code);
+            assert_eq!(refs.declaration().def_kind, *kind, "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_find_all_refs_lifetime_respects_search_scope() {
+        let code = r#"
+            struct Foo<'a>(&'a str);
+
+            impl<'a> Foo<'a> {
+                fn get<'b>(&'b self) -> &'a<|> str {
+                    fn nested<'a>(x: &'a str) -> &'a str { x }
+                    nested(self.0)
+                }
+            }
+        "#;
+
+        let (analysis, pos) = single_file_with_position(code);
+        let config = FindAllRefsConfig {
+            search_scope: Some(SearchScope::function_body(
+                pos.file_id,
+                TextRange::new(100.into(), 120.into()),
+            )),
+            ..FindAllRefsConfig::default()
+        };
+        let refs = analysis.find_all_refs(pos, &config).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "'a LIFETIME_PARAM FileId(1) 56..58 56..58 Other",
+            &["FileId(1) 111..113 Other"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_lifetime() {
+        let code = r#"
+            struct Foo<'a>(&'a str);
+
+            impl<'a> Foo<'a> {
+                fn get<'b>(&'b self) -> &'a<|> str {
+                    fn nested<'a>(x: &'a str) -> &'a str { x }
+                    nested(self.0)
+                }
+            }
+        "#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "'a LIFETIME_PARAM FileId(1) 56..58 56..58 Other",
+            &["FileId(1) 64..66 Other", "FileId(1) 111..113 Other"],
+        );
+    }
+
+    #[test]
+    fn test_find_usages_with_stops_early() {
+        let code = r#"
+            fn foo<|>() {}
+
+            fn main() {
+                foo();
+                foo();
+                foo();
+            }
+        "#;
+
+        let (mock, position) =
+            MockAnalysis::with_files_and_position(&format!("//- /main.rs\n{}", code));
+        let host = mock.analysis_host();
+        let refs = host
+            .analysis()
+            .find_all_refs(position, &FindAllRefsConfig::default())
+            .unwrap()
+            .unwrap();
+        let def = refs.definition().unwrap();
+        let db = host.raw_database();
+        assert_eq!(def.find_usages(db, None).len(), 3);
+
+        let mut seen = Vec::new();
+        def.find_usages_with(db, None, &mut |reference| {
+            seen.push(reference);
+            false
+        });
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].file_range.range, refs.references()[0].file_range.range);
+    }
+
+    #[test]
+    fn test_find_all_refs_exposes_definition() {
+        let code = r#"
+            struct Foo;
+
+            fn main() {
+                let f: Foo<|>;
+            }
+        "#;
+
+        let (_, refs) = get_all_refs(code);
+        assert!(matches!(
+            refs.definition(),
+            Some(Definition::ModuleDef(ModuleDef::Adt(Adt::Struct(_))))
+        ));
+
+        let code = r#"
+            struct Foo<'a>(&'a<|> str);
+        "#;
+
+        let (_, refs) = get_all_refs(code);
+        assert_eq!(refs.definition(), None);
+    }
+
+    #[test]
+    fn test_find_all_refs_to_lsp_locations() {
+        let (mock, position) = MockAnalysis::with_files_and_position(
+            r#"
+            //- /main.rs
+            struct Foo;
+
+            fn main() {
+                let f: Foo<|>;
+            }
+            "#,
+        );
+        let host = mock.analysis_host();
+        let refs = host
+            .analysis()
+            .find_all_refs(position, &FindAllRefsConfig::default())
+            .unwrap()
+            .unwrap();
+
+        let expected = serde_json::json!([
+            {
+                "uri": "file:///[..]main.rs",
+                "range": { "start": { "line": 0, "character": 7 }, "end": { "line": 0, "character": 10 } },
+            },
+            {
+                "uri": "file:///[..]main.rs",
+                "range": { "start": { "line": 3, "character": 11 }, "end": { "line": 3, "character": 14 } },
+            },
+        ]);
+        let actual = refs.to_lsp_locations(host.raw_database());
+        if let Some((expected_part, actual_part)) = find_mismatch(&expected, &actual) {
+            panic!(
+                "{}\n\nexpected:\n{}\n\nactual:\n{}",
+                "locations do not match",
+                serde_json::to_string_pretty(expected_part).unwrap(),
+                serde_json::to_string_pretty(actual_part).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_all_refs_sorted_by_file_and_offset() {
+        let code = r#"
+            //- /lib.rs
+            pub mod foo;
+            pub mod bar;
+
+            fn f() -> bar::Bar { bar::Bar }
+
+            //- /foo.rs
+            use crate::bar::Bar;
+
+            fn g(b: Bar) -> Bar { b }
+
+            //- /bar.rs
+            pub struct Bar<|>;
+
+            impl Bar {
+                fn new() -> Self { Bar }
+            }
+        "#;
+
+        let (analysis, pos) = analysis_and_position(code);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "Bar STRUCT_DEF FileId(3) 0..15 11..14 Other pub",
+            &[
+                "FileId(1) 41..44 Other",
+                "FileId(1) 52..55 Other",
+                "FileId(2) 16..19 Other",
+                "FileId(2) 29..32 Other",
+                "FileId(2) 37..40 Other",
+                "FileId(3) 21..24 Other",
+                "FileId(3) 43..47 SelfType",
+                "FileId(3) 50..53 Other",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_two_modules() {
+        let code = r#"
+            //- /lib.rs
+            pub mod foo;
+            pub mod bar;
+
+            fn f() {
+                let i = foo::Foo { n: 5 };
+            }
+
+            //- /foo.rs
+            use crate::bar;
+
+            pub struct Foo {
+                pub n: u32,
+            }
+
+            fn f() {
+                let i = bar::Bar { n: 5 };
+            }
+
+            //- /bar.rs
+            use crate::foo;
+
+            pub struct Bar {
+                pub n: u32,
+            }
+
+            fn f() {
+                let i = foo::Foo<|> { n: 5 };
+            }
+        "#;
+
+        let (analysis, pos) = analysis_and_position(code);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "Foo STRUCT_DEF FileId(2) 16..50 27..30 Other pub",
+            &["FileId(1) 52..55 StructLiteral", "FileId(3) 77..80 StructLiteral"],
+        );
+    }
+
+    // `mod foo;` is not in the results because `foo` is an `ast::Name`.
+    // So, there are two references: the first one is a definition of the `foo` module,
+    // which is the whole `foo.rs`, and the second one is in `use foo::Foo`.
     #[test]
     fn test_find_all_refs_decl_module() {
         let code = r#"
@@ -454,8 +1689,13 @@ mod tests {
         "#;
 
         let (analysis, pos) = analysis_and_position(code);
-        let refs = analysis.find_all_refs(pos, None).unwrap().unwrap();
-        check_result(refs, "foo SOURCE_FILE FileId(2) 0..35 Other", &["FileId(1) 13..16 Other"]);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "foo SOURCE_FILE FileId(2) 0..35 Other",
+            &["FileId(1) 13..16 Other"],
+        );
     }
 
     #[test]
@@ -479,10 +1719,11 @@ mod tests {
         "#;
 
         let (analysis, pos) = analysis_and_position(code);
-        let refs = analysis.find_all_refs(pos, None).unwrap().unwrap();
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
         check_result(
+            &analysis,
             refs,
-            "Foo STRUCT_DEF FileId(3) 0..41 18..21 Other",
+            "Foo STRUCT_DEF FileId(3) 0..41 18..21 Other pub(in crate::foo)",
             &["FileId(2) 20..23 Other", "FileId(2) 46..49 StructLiteral"],
         );
     }
@@ -507,22 +1748,138 @@ mod tests {
         let bar = mock.id_of("/bar.rs");
         let analysis = mock.analysis();
 
-        let refs = analysis.find_all_refs(pos, None).unwrap().unwrap();
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
         check_result(
+            &analysis,
             refs,
-            "quux FN_DEF FileId(1) 18..34 25..29 Other",
+            "quux FN_DEF FileId(1) 18..34 25..29 Other pub",
             &["FileId(2) 16..20 StructLiteral", "FileId(3) 16..20 StructLiteral"],
         );
 
-        let refs =
-            analysis.find_all_refs(pos, Some(SearchScope::single_file(bar))).unwrap().unwrap();
+        let refs = analysis
+            .find_all_refs(
+                pos,
+                &FindAllRefsConfig {
+                    search_scope: Some(SearchScope::single_file(bar)),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .unwrap();
         check_result(
+            &analysis,
             refs,
-            "quux FN_DEF FileId(1) 18..34 25..29 Other",
+            "quux FN_DEF FileId(1) 18..34 25..29 Other pub",
             &["FileId(3) 16..20 StructLiteral"],
         );
     }
 
+    #[test]
+    fn test_find_all_refs_with_module_subtree_scope() {
+        let code = r#"
+            //- /lib.rs
+            mod foo;
+            mod bar;
+
+            pub fn quux<|>() {}
+
+            //- /foo.rs
+            mod inner;
+            mod nested {
+                fn g() { crate::quux(); }
+            }
+            fn f() { super::quux(); }
+
+            //- /foo/inner.rs
+            fn f() { crate::quux(); }
+
+            //- /bar.rs
+            fn f() { super::quux(); }
+        "#;
+
+        let (mock, pos) = MockAnalysis::with_files_and_position(code);
+        let foo = mock.id_of("/foo.rs");
+        let host = mock.analysis_host();
+        let module = Semantics::new(host.raw_database()).to_module_def(foo).unwrap();
+        let scope = SearchScope::module_subtree(host.raw_database(), module);
+
+        let analysis = host.analysis();
+        let refs = analysis
+            .find_all_refs(
+                pos,
+                &FindAllRefsConfig { search_scope: Some(scope), ..Default::default() },
+            )
+            .unwrap()
+            .unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "quux FN_DEF FileId(1) 18..34 25..29 Other pub",
+            &[
+                "FileId(2) 44..48 StructLiteral",
+                "FileId(2) 72..76 StructLiteral",
+                "FileId(3) 16..20 StructLiteral",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_with_union_scope() {
+        let code = r#"
+            //- /lib.rs
+            mod foo;
+            mod bar;
+            mod baz;
+
+            pub fn quux<|>() {}
+
+            //- /foo.rs
+            fn f() { super::quux(); }
+
+            //- /bar.rs
+            fn f() { super::quux(); }
+
+            //- /baz.rs
+            fn f() { super::quux(); }
+        "#;
+
+        let (mock, pos) = MockAnalysis::with_files_and_position(code);
+        let foo = mock.id_of("/foo.rs");
+        let bar = mock.id_of("/bar.rs");
+        let baz = mock.id_of("/baz.rs");
+        let analysis = mock.analysis();
+
+        let scope = SearchScope::single_file(foo).union(&SearchScope::single_file(bar));
+        let refs = analysis
+            .find_all_refs(
+                pos,
+                &FindAllRefsConfig { search_scope: Some(scope), ..Default::default() },
+            )
+            .unwrap()
+            .unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "quux FN_DEF FileId(1) 27..43 34..38 Other pub",
+            &["FileId(2) 16..20 StructLiteral", "FileId(3) 16..20 StructLiteral"],
+        );
+
+        let scope = SearchScope::files(&[foo, baz]).intersection(&SearchScope::files(&[baz]));
+        let refs = analysis
+            .find_all_refs(
+                pos,
+                &FindAllRefsConfig { search_scope: Some(scope), ..Default::default() },
+            )
+            .unwrap()
+            .unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "quux FN_DEF FileId(1) 27..43 34..38 Other pub",
+            &["FileId(4) 16..20 StructLiteral"],
+        );
+    }
+
     #[test]
     fn test_find_all_refs_macro_def() {
         let code = r#"
@@ -530,16 +1887,123 @@ mod tests {
         macro_rules! m1<|> { () => (()) }
 
         fn foo() {
-            m1();
-            m1();
+            m1!();
+            m1!();
         }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "m1 MACRO_CALL FileId(1) 9..63 46..48 Other",
-            &["FileId(1) 96..98 StructLiteral", "FileId(1) 114..116 StructLiteral"],
+            &["FileId(1) 96..98 MacroCall", "FileId(1) 115..117 MacroCall"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_macro_def_in_other_modules() {
+        let code = r#"
+            //- /lib.rs
+            #[macro_use]
+            mod macros;
+            mod foo;
+
+            //- /macros.rs
+            #[macro_export]
+            macro_rules! m1<|> { () => (()) }
+
+            //- /foo.rs
+            fn f() {
+                m1!();
+                crate::m1!();
+            }
+        "#;
+
+        let (analysis, pos) = analysis_and_position(code);
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "m1 MACRO_CALL FileId(2) 0..46 29..31 Other",
+            &["FileId(3) 13..15 MacroCall", "FileId(3) 31..33 MacroCall"],
+        );
+    }
+
+    #[test]
+    fn test_reference_counts() {
+        let code = r#"
+        struct Foo { i: i32 }
+
+        fn foo() {
+            let mut i<|> = 0;
+            i = i + 1;
+            let foo = Foo { i };
+        }"#;
+
+        let (_, refs) = get_all_refs(code);
+        let kinds = refs.kind_counts();
+        assert_eq!(kinds.len(), 2);
+        assert_eq!(kinds[&ReferenceKind::Other], 3);
+        assert_eq!(kinds[&ReferenceKind::FieldShorthandForLocal], 1);
+        let accesses = refs.access_counts();
+        assert_eq!(accesses[&ReferenceAccess::Write], 2);
+        assert_eq!(accesses[&ReferenceAccess::Read], 2);
+
+        let refs = refs.references_only();
+        assert_eq!(refs.kind_counts().values().sum::<usize>(), refs.len());
+        assert_eq!(refs.kind_counts()[&ReferenceKind::Other], 2);
+        assert_eq!(refs.access_counts()[&ReferenceAccess::Write], 1);
+    }
+
+    #[test]
+    fn test_dedup_references() {
+        let code = r#"
+            fn foo<|>() {}
+            fn bar() { foo(); foo(); }
+        "#;
+
+        let mut refs = get_all_refs(code).1.references;
+        assert_eq!(refs.len(), 2);
+        let mut duplicate = refs[0].clone();
+        duplicate.access = Some(ReferenceAccess::Write);
+        refs.insert(1, duplicate);
+
+        super::dedup_references(&mut refs);
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].access, None);
+        assert_ne!(refs[0].file_range.range, refs[1].file_range.range);
+    }
+
+    #[test]
+    fn test_find_all_refs_with_enclosing() {
+        let code = r#"
+            struct Foo<|>;
+            const C: Foo = Foo;
+            fn bar() -> Foo { Foo }
+            impl Foo {}
+        "#;
+
+        let (analysis, pos) = single_file_with_position(code);
+        let config = FindAllRefsConfig { with_enclosing: true, ..FindAllRefsConfig::default() };
+        let refs = analysis.find_all_refs(pos, &config).unwrap().unwrap();
+        let enclosing: Vec<_> = refs
+            .references()
+            .iter()
+            .map(|r| r.enclosing.as_ref().map(|it| (it.name.to_string(), it.kind)))
+            .collect();
+        assert_eq!(
+            enclosing,
+            vec![
+                Some(("C".to_string(), SyntaxKind::CONST_DEF)),
+                Some(("C".to_string(), SyntaxKind::CONST_DEF)),
+                Some(("bar".to_string(), SyntaxKind::FN_DEF)),
+                Some(("bar".to_string(), SyntaxKind::FN_DEF)),
+                Some(("Foo".to_string(), SyntaxKind::IMPL_DEF)),
+            ]
         );
+
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        assert!(refs.references().iter().all(|r| r.enclosing.is_none()));
     }
 
     #[test]
@@ -550,14 +2014,63 @@ mod tests {
             i = i + 1;
         }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "i BIND_PAT FileId(1) 40..41 Other Write",
             &["FileId(1) 59..60 Other Write", "FileId(1) 63..64 Other Read"],
         );
     }
 
+    #[test]
+    fn test_highlight_compound_assignment_and_mut_method() {
+        let code = r#"
+        struct Vec;
+        impl Vec {
+            fn push(&mut self, x: i32) {}
+            fn len(&self) -> usize { 0 }
+        }
+
+        fn foo() {
+            let mut v<|> = Vec;
+            v.push(1);
+            v.len() == 1;
+            let mut i = v.len();
+            i += 1;
+        }"#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "v BIND_PAT FileId(1) 173..174 Other Write",
+            &[
+                "FileId(1) 194..195 Other Write",
+                "FileId(1) 217..218 Other Read",
+                "FileId(1) 255..256 Other Read",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_highlight_compound_assignment() {
+        let code = r#"
+        fn foo() {
+            let mut i<|> = 0;
+            i += 1;
+            i == 1;
+        }"#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "i BIND_PAT FileId(1) 40..41 Other Write",
+            &["FileId(1) 59..60 Other Write", "FileId(1) 79..80 Other Read"],
+        );
+    }
+
     #[test]
     fn test_basic_highlight_field_read_write() {
         let code = r#"
@@ -570,8 +2083,9 @@ mod tests {
             s.f<|> = 0;
         }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
             "f RECORD_FIELD_DEF FileId(1) 32..38 32..33 Other",
             &["FileId(1) 96..97 Other Read", "FileId(1) 117..118 Other Write"],
@@ -586,8 +2100,13 @@ mod tests {
             i = 1;
         }"#;
 
-        let refs = get_all_refs(code);
-        check_result(refs, "i BIND_PAT FileId(1) 36..37 Other", &["FileId(1) 51..52 Other Write"]);
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "i BIND_PAT FileId(1) 36..37 Other",
+            &["FileId(1) 51..52 Other Write"],
+        );
     }
 
     #[test]
@@ -607,14 +2126,105 @@ mod tests {
             let _f = foo::Foo::new();
         }"#;
 
-        let refs = get_all_refs(code);
+        let (analysis, refs) = get_all_refs(code);
         check_result(
+            &analysis,
             refs,
-            "new FN_DEF FileId(1) 87..150 94..97 Other",
+            "new FN_DEF FileId(1) 87..150 94..97 Other pub",
             &["FileId(1) 227..230 StructLiteral"],
         );
     }
 
+    #[test]
+    fn test_find_all_refs_self_type() {
+        let code = r#"
+        struct Foo;
+
+        impl Foo {
+            fn new() -> Self<|> {
+                Foo
+            }
+        }
+
+        impl Default for Foo {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        struct Bar;
+        impl Bar {
+            fn new() -> Self { Bar }
+        }"#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "Foo STRUCT_DEF FileId(1) 9..20 16..19 Other",
+            &[
+                "FileId(1) 35..38 Other",
+                "FileId(1) 65..69 SelfType",
+                "FileId(1) 88..91 Other",
+                "FileId(1) 142..145 Other",
+                "FileId(1) 176..180 SelfType",
+                "FileId(1) 199..203 SelfType",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_find_self_qualified_method_refs() {
+        let code = r#"
+        struct Foo;
+
+        impl Foo {
+            fn new<|>() -> Foo {
+                Foo
+            }
+
+            fn default() -> Foo {
+                Self::new()
+            }
+        }
+
+        fn main() {
+            let _f = Foo::new();
+        }"#;
+
+        let (analysis, refs) = get_all_refs(code);
+        check_result(
+            &analysis,
+            refs,
+            "new FN_DEF FileId(1) 53..104 56..59 Other",
+            &["FileId(1) 162..165 StructLiteral", "FileId(1) 239..242 StructLiteral"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_reports_visibility() {
+        let code = r#"
+            mod foo {
+                pub(crate) fn quux() {}
+                fn spam() {}
+                mod bar {
+                    pub(super) fn eggs() {}
+                }
+            }
+        "#;
+        let (analysis, refs) = get_all_refs(&code.replace("quux", "quux<|>"));
+        check_result(&analysis, refs, "quux FN_DEF FileId(1) 39..62 53..57 Other pub(crate)", &[]);
+        let (analysis, refs) = get_all_refs(&code.replace("spam", "spam<|>"));
+        check_result(&analysis, refs, "spam FN_DEF FileId(1) 79..91 82..86 Other", &[]);
+        let (analysis, refs) = get_all_refs(&code.replace("eggs", "eggs<|>"));
+        check_result(
+            &analysis,
+            refs,
+            "eggs FN_DEF FileId(1) 138..161 152..156 Other pub(in crate::foo)",
+            &[],
+        );
+    }
+
     #[test]
     fn test_find_all_refs_nested_module() {
         let code = r#"
@@ -634,21 +2244,74 @@ mod tests {
         "#;
 
         let (analysis, pos) = analysis_and_position(code);
-        let refs = analysis.find_all_refs(pos, None).unwrap().unwrap();
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
         check_result(
+            &analysis,
             refs,
             "f FN_DEF FileId(1) 25..34 28..29 Other",
             &["FileId(2) 11..12 Other", "FileId(2) 27..28 StructLiteral"],
         );
     }
 
-    fn get_all_refs(text: &str) -> ReferenceSearchResult {
+    #[test]
+    fn test_find_all_refs_in_selection() {
+        let code = r#"
+            fn main() {
+                let a = 1;
+                let b = 2;
+                <|>let _ = a + b + a;<|>
+            }
+        "#;
+
+        let (analysis, frange) = single_file_with_range(code);
+        let res = analysis.find_all_refs_in_selection(frange).unwrap();
+        assert_eq!(res.len(), 2);
+        check_result(
+            &analysis,
+            res[0].info.clone(),
+            "a BIND_PAT FileId(1) 45..46 Other",
+            &["FileId(1) 103..104 Other Read", "FileId(1) 111..112 Other Read"],
+        );
+        check_result(
+            &analysis,
+            res[1].info.clone(),
+            "b BIND_PAT FileId(1) 72..73 Other",
+            &["FileId(1) 107..108 Other Read"],
+        );
+    }
+
+    #[test]
+    fn test_find_all_refs_at_named_marker() {
+        let code = r#"
+            //- /lib.rs
+            fn foo<|def|>() {}
+            fn bar() { foo(); }
+        "#;
+
+        let (analysis, pos) = analysis_at_marker(code, Some("def"));
+        let refs = analysis.find_all_refs(pos, &FindAllRefsConfig::default()).unwrap().unwrap();
+        check_result(
+            &analysis,
+            refs,
+            "foo FN_DEF FileId(1) 0..11 3..6 Other",
+            &["FileId(1) 23..26 StructLiteral"],
+        );
+    }
+
+    fn get_all_refs(text: &str) -> (Analysis, ReferenceSearchResult) {
         let (analysis, position) = single_file_with_position(text);
-        analysis.find_all_refs(position, None).unwrap().unwrap()
+        let refs =
+            analysis.find_all_refs(position, &FindAllRefsConfig::default()).unwrap().unwrap();
+        (analysis, refs)
     }
 
-    fn check_result(res: ReferenceSearchResult, expected_decl: &str, expected_refs: &[&str]) {
-        res.declaration().assert_match(expected_decl);
+    fn check_result(
+        analysis: &Analysis,
+        res: ReferenceSearchResult,
+        expected_decl: &str,
+        expected_refs: &[&str],
+    ) {
+        res.declaration().assert_match(&analysis.db, res.definition(), expected_decl);
         assert_eq!(res.references.len(), expected_refs.len());
         res.references()
             .iter()
@@ -657,20 +2320,47 @@ mod tests {
     }
 
     impl Declaration {
-        fn debug_render(&self) -> String {
+        fn debug_render(&self, db: &RootDatabase, def: Option<Definition>) -> String {
             let mut s = format!("{} {:?}", self.nav.debug_render(), self.kind);
             if let Some(access) = self.access {
                 s.push_str(&format!(" {:?}", access));
             }
+            match self.visibility {
+                Some(Visibility::Public) => s.push_str(" pub"),
+                Some(Visibility::Module(id)) => {
+                    let module = Module::from(id);
+                    // Private items are visible in the module they are defined in.
+                    if def.and_then(|def| def.module(db)) != Some(module) {
+                        s.push_str(&format!(" {}", restricted_visibility(db, module)));
+                    }
+                }
+                None => (),
+            }
             s
         }
 
-        fn assert_match(&self, expected: &str) {
-            let actual = self.debug_render();
+        fn assert_match(&self, db: &RootDatabase, def: Option<Definition>, expected: &str) {
+            let actual = self.debug_render(db, def);
             test_utils::assert_eq_text!(expected.trim(), actual.trim(),);
         }
     }
 
+    /// Renders the visibility restricted to `module` as `pub(crate)` or
+    /// `pub(in crate::path)`.
+    fn restricted_visibility(db: &RootDatabase, module: Module) -> String {
+        if module == module.crate_root(db) {
+            return "pub(crate)".to_string();
+        }
+        let path = module
+            .path_to_root(db)
+            .into_iter()
+            .rev()
+            .filter_map(|it| it.name(db))
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        format!("pub(in crate::{})", path.join("::"))
+    }
+
     fn ref_debug_render(r: &Reference) -> String {
         let mut s = format!("{:?} {:?} {:?}", r.file_range.file_id, r.file_range.range, r.kind);
         if let Some(access) = r.access {
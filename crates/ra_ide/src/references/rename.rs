@@ -1,45 +1,125 @@
 //! FIXME: write short doc here
 
-use hir::{ModuleSource, Semantics};
+use hir::{Local, ModuleDef, ModuleSource, ScopeDef, Semantics};
 use ra_db::{RelativePath, RelativePathBuf, SourceDatabaseExt};
-use ra_ide_db::RootDatabase;
+use ra_ide_db::{
+    defs::{classify_name, classify_name_ref, Definition},
+    search::{self, SearchScope},
+    RootDatabase,
+};
 use ra_syntax::{
-    algo::find_node_at_offset, ast, ast::TypeAscriptionOwner, lex_single_valid_syntax_kind,
-    AstNode, SyntaxKind, SyntaxNode, SyntaxToken,
+    algo::find_node_at_offset,
+    ast::{self, NameOwner, TypeAscriptionOwner, VisibilityOwner},
+    lex_single_valid_syntax_kind, AstNode, SyntaxKind, SyntaxNode, SyntaxToken,
 };
 use ra_text_edit::TextEdit;
 use std::convert::TryInto;
 use test_utils::mark;
 
 use crate::{
-    references::find_all_refs, FilePosition, FileSystemEdit, RangeInfo, Reference, ReferenceKind,
-    SourceChange, SourceFileEdit, TextRange, TextSize,
+    display::TryToNav,
+    references::{
+        find_all_refs, find_definition, lifetime_param, FindAllRefsConfig, ReferenceSearchResult,
+    },
+    FileId, FilePosition, FileRange, FileSystemEdit, NavigationTarget, RangeInfo, Reference,
+    ReferenceKind, SourceChange, SourceFileEdit, TextRange, TextSize,
 };
 
+/// A rename which can't be applied, like one to an invalid name or one which
+/// would clash with an existing definition.
+#[derive(Debug)]
+pub struct RenameError {
+    pub message: String,
+    /// The definition which already uses the new name, if any.
+    pub conflict: Option<NavigationTarget>,
+}
+
+impl std::fmt::Display for RenameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RenameError {}
+
 pub(crate) fn rename(
     db: &RootDatabase,
     position: FilePosition,
     new_name: &str,
-) -> Option<RangeInfo<SourceChange>> {
-    match lex_single_valid_syntax_kind(new_name)? {
-        SyntaxKind::IDENT | SyntaxKind::UNDERSCORE => (),
-        SyntaxKind::SELF_KW => return rename_to_self(db, position),
-        _ => return None,
-    }
-
+) -> Result<Option<RangeInfo<SourceChange>>, RangeInfo<RenameError>> {
     let sema = Semantics::new(db);
     let source_file = sema.parse(position.file_id);
     let syntax = source_file.syntax();
-    if let Some((ast_name, ast_module)) = find_name_and_module_at_offset(syntax, position) {
+    if syntax.token_at_offset(position.offset).any(|t| t.kind() == SyntaxKind::LIFETIME) {
+        return rename_lifetime(db, position, new_name);
+    }
+
+    // Keywords are only accepted in their raw form, like `r#match`, which lexes
+    // as an identifier.
+    match lex_single_valid_syntax_kind(new_name) {
+        Some(SyntaxKind::IDENT) | Some(SyntaxKind::UNDERSCORE) => (),
+        Some(SyntaxKind::SELF_KW) => return Ok(rename_to_self(db, position)),
+        _ => {
+            let range = syntax
+                .token_at_offset(position.offset)
+                .find(|t| t.kind() == SyntaxKind::IDENT)
+                .map_or(TextRange::empty(position.offset), |t| t.text_range());
+            let message = format!("`{}` is not a valid identifier", new_name);
+            return Err(RangeInfo::new(range, RenameError { message, conflict: None }));
+        }
+    }
+
+    let res = if let Some((ast_name, ast_module)) = find_name_and_module_at_offset(syntax, position)
+    {
         let range = ast_name.syntax().text_range();
         rename_mod(&sema, &ast_name, &ast_module, position, new_name)
             .map(|info| RangeInfo::new(range, info))
+    } else if let Some((range, decl_position, ast_name, ast_module)) =
+        find_module_decl_for_path(&sema, syntax, position)
+    {
+        rename_mod(&sema, &ast_name, &ast_module, decl_position, new_name)
+            .map(|info| RangeInfo::new(range, info))
     } else if let Some(self_token) =
         syntax.token_at_offset(position.offset).find(|t| t.kind() == SyntaxKind::SELF_KW)
     {
         rename_self_to_param(db, position, self_token, new_name)
+    } else if let Some((range, alias)) = find_alias_at_offset(&sema, syntax, position) {
+        rename_alias(&sema, position, alias, new_name).map(|info| RangeInfo::new(range, info))
     } else {
-        rename_reference(sema.db, position, new_name)
+        return rename_reference(&sema, position, new_name);
+    };
+    Ok(res)
+}
+
+/// Returns the range `rename` would rename at `position`, without searching
+/// for references or checking the new name for conflicts.
+pub(crate) fn prepare_rename(db: &RootDatabase, position: FilePosition) -> Option<TextRange> {
+    let sema = Semantics::new(db);
+    let source_file = sema.parse(position.file_id);
+    let syntax = source_file.syntax();
+    if let Some(lifetime) =
+        syntax.token_at_offset(position.offset).find(|t| t.kind() == SyntaxKind::LIFETIME)
+    {
+        lifetime_param(&lifetime)?;
+        return Some(lifetime.text_range());
+    }
+
+    if let Some((ast_name, _)) = find_name_and_module_at_offset(syntax, position) {
+        Some(ast_name.syntax().text_range())
+    } else if let Some((range, ..)) = find_module_decl_for_path(&sema, syntax, position) {
+        Some(range)
+    } else if let Some(self_token) =
+        syntax.token_at_offset(position.offset).find(|t| t.kind() == SyntaxKind::SELF_KW)
+    {
+        let range = ast::SelfParam::cast(self_token.parent())
+            .map_or(self_token.text_range(), |p| p.syntax().text_range());
+        Some(range)
+    } else if let Some((range, _)) = find_alias_at_offset(&sema, syntax, position) {
+        Some(range)
+    } else {
+        let (RangeInfo { range, info: def }, _) = find_definition(&sema, syntax, position)?;
+        def.try_to_nav(db)?;
+        Some(range)
     }
 }
 
@@ -52,6 +132,122 @@ fn find_name_and_module_at_offset(
     Some((ast_name, ast_module))
 }
 
+/// Finds the `mod foo;` declaring the module referred to by a path segment like
+/// `foo` in `foo::bar()`, so that renaming it also renames the module file.
+fn find_module_decl_for_path(
+    sema: &Semantics<RootDatabase>,
+    syntax: &SyntaxNode,
+    position: FilePosition,
+) -> Option<(TextRange, FilePosition, ast::Name, ast::Module)> {
+    let name_ref = find_node_at_offset::<ast::NameRef>(syntax, position.offset)?;
+    let module = match classify_name_ref(sema, &name_ref)?.definition() {
+        Definition::ModuleDef(ModuleDef::Module(it)) => it,
+        _ => return None,
+    };
+    // `self`, `super` and aliases don't spell out the module name.
+    if module.name(sema.db)?.to_string() != name_ref.text().as_str() {
+        return None;
+    }
+    let decl = module.declaration_source(sema.db)?;
+    let decl_position = FilePosition {
+        file_id: decl.file_id.original_file(sema.db),
+        offset: decl.value.name()?.syntax().text_range().start(),
+    };
+    let decl_file = sema.parse(decl_position.file_id);
+    let (ast_name, ast_module) = find_name_and_module_at_offset(decl_file.syntax(), decl_position)?;
+    Some((name_ref.syntax().text_range(), decl_position, ast_name, ast_module))
+}
+
+/// Finds the `use ... as Alias` the cursor is on, either on the alias itself or
+/// on a usage of it.
+fn find_alias_at_offset(
+    sema: &Semantics<RootDatabase>,
+    syntax: &SyntaxNode,
+    position: FilePosition,
+) -> Option<(TextRange, ast::Alias)> {
+    if let Some(name) = find_node_at_offset::<ast::Name>(syntax, position.offset) {
+        let alias = ast::Alias::cast(name.syntax().parent()?)?;
+        return Some((name.syntax().text_range(), alias));
+    }
+
+    let name_ref = find_node_at_offset::<ast::NameRef>(syntax, position.offset)?;
+    let def = classify_name_ref(sema, &name_ref)?.definition();
+    if def.name(sema.db)?.to_string() == name_ref.text().as_str() {
+        return None;
+    }
+    let alias = syntax.descendants().filter_map(ast::Alias::cast).find(|alias| {
+        alias.name().map_or(false, |name| {
+            name.text() == name_ref.text()
+                && classify_name(sema, &name).map(|it| it.definition()) == Some(def)
+        })
+    })?;
+    Some((name_ref.syntax().text_range(), alias))
+}
+
+/// Renames only the alias and its usages, leaving the aliased item alone.
+fn rename_alias(
+    sema: &Semantics<RootDatabase>,
+    position: FilePosition,
+    alias: ast::Alias,
+    new_name: &str,
+) -> Option<SourceChange> {
+    let name = alias.name()?;
+    let def = classify_name(sema, &name)?.definition();
+    let use_item = alias.syntax().ancestors().find_map(ast::UseItem::cast)?;
+
+    let mut ranges =
+        vec![FileRange { file_id: position.file_id, range: name.syntax().text_range() }];
+    for (file_id, search_range) in alias_search_scope(sema, &use_item)? {
+        if !sema.db.file_text(file_id).contains(name.text().as_str()) {
+            continue;
+        }
+        let file = sema.parse(file_id);
+        let usages =
+            file.syntax().descendants().filter_map(ast::NameRef::cast).filter(|name_ref| {
+                search_range
+                    .map_or(true, |range| range.contains_range(name_ref.syntax().text_range()))
+                    && name_ref.text() == name.text()
+                    && classify_name_ref(sema, name_ref).map(|it| it.definition()) == Some(def)
+            });
+        ranges.extend(
+            usages.map(|name_ref| FileRange { file_id, range: name_ref.syntax().text_range() }),
+        );
+    }
+    ranges.sort_by_key(|it| (it.file_id, it.range.start()));
+
+    let edits = ranges
+        .into_iter()
+        .map(|frange| SourceFileEdit {
+            file_id: frange.file_id,
+            edit: TextEdit::replace(frange.range, new_name.into()),
+        })
+        .collect();
+    Some(SourceChange::source_file_edits(edits))
+}
+
+/// An alias is visible in the module of its `use` and the module's
+/// descendants, or in the whole crate and its dependants if it is re-exported.
+fn alias_search_scope(
+    sema: &Semantics<RootDatabase>,
+    use_item: &ast::UseItem,
+) -> Option<SearchScope> {
+    let db = sema.db;
+    let module = sema.scope(use_item.syntax()).module()?;
+    let krate = module.krate();
+    let scope = match use_item.visibility().map(|it| it.kind()) {
+        None | Some(ast::VisibilityKind::PubSelf) => SearchScope::module_subtree(db, module),
+        Some(ast::VisibilityKind::Pub) => krate
+            .reverse_dependencies(db)
+            .into_iter()
+            .filter_map(|rev_dep| rev_dep.root_module(db))
+            .fold(SearchScope::module_subtree(db, krate.root_module(db)?), |acc, root| {
+                acc.union(&SearchScope::module_subtree(db, root))
+            }),
+        Some(_) => SearchScope::module_subtree(db, krate.root_module(db)?),
+    };
+    Some(scope)
+}
+
 fn source_edit_from_reference(reference: Reference, new_name: &str) -> SourceFileEdit {
     let mut replacement_text = String::new();
     let file_id = reference.file_range.file_id;
@@ -120,7 +316,9 @@ fn rename_mod(
     };
     source_file_edits.push(edit);
 
-    if let Some(RangeInfo { range: _, info: refs }) = find_all_refs(sema.db, position, None) {
+    if let Some(RangeInfo { range: _, info: refs }) =
+        find_all_refs(sema.db, position, &FindAllRefsConfig::default())
+    {
         let ref_edits = refs
             .references
             .into_iter()
@@ -147,7 +345,8 @@ fn rename_to_self(db: &RootDatabase, position: FilePosition) -> Option<RangeInfo
         _ => return None, // not renaming other types
     };
 
-    let RangeInfo { range, info: refs } = find_all_refs(db, position, None)?;
+    let RangeInfo { range, info: refs } =
+        find_all_refs(db, position, &FindAllRefsConfig::default())?;
 
     let param_range = first_param.syntax().text_range();
     let (param_ref, usages): (Vec<Reference>, Vec<Reference>) = refs
@@ -238,22 +437,210 @@ fn rename_self_to_param(
 }
 
 fn rename_reference(
+    sema: &Semantics<RootDatabase>,
+    position: FilePosition,
+    new_name: &str,
+) -> Result<Option<RangeInfo<SourceChange>>, RangeInfo<RenameError>> {
+    let RangeInfo { range, info: refs } =
+        match find_all_refs(sema.db, position, &FindAllRefsConfig::default()) {
+            Some(it) => it,
+            None => return Ok(None),
+        };
+
+    // Renaming a single impl method would leave it out of sync with its
+    // trait, so the trait method is renamed along with all of its impls.
+    if let Some(Definition::ModuleDef(ModuleDef::Function(func))) = refs.definition() {
+        if let Some(trait_item) = search::implemented_trait_item(sema.db, func) {
+            mark::hit!(test_rename_impl_method_renames_trait_method);
+            let FileRange { file_id, range: trait_range } = trait_item.file_range;
+            let trait_position = FilePosition { file_id, offset: trait_range.start() };
+            return match rename_reference(sema, trait_position, new_name) {
+                Ok(change) => Ok(change.map(|it| RangeInfo::new(range, it.info))),
+                Err(err) => Err(RangeInfo::new(range, err.info)),
+            };
+        }
+    }
+
+    if let Some(conflict) = find_name_conflict(sema, &refs, new_name) {
+        return Err(RangeInfo::new(range, conflict));
+    }
+
+    // A local bound by a record pattern shorthand, like `x` in
+    // `let Foo { x } = foo;`, has to keep the field name in the pattern.
+    let shorthand_binding = match refs.definition() {
+        Some(Definition::Local(_)) => {
+            let nav = &refs.declaration().nav;
+            let decl = FileRange { file_id: nav.file_id(), range: nav.range() };
+            record_pat_shorthand_binding(sema, decl).map(|pat| (decl, pat))
+        }
+        _ => None,
+    };
+
+    let mut references: Vec<_> = refs.into_iter().collect();
+    // Method calls on a concrete type resolve to the impl method rather than
+    // to the trait method, so the usages of every impl are renamed as well.
+    let impl_references: Vec<_> = references
+        .iter()
+        .filter(|reference| reference.kind == ReferenceKind::TraitImplItem)
+        .filter_map(|reference| {
+            let FileRange { file_id, range } = reference.file_range;
+            let position = FilePosition { file_id, offset: range.start() };
+            find_all_refs(sema.db, position, &FindAllRefsConfig::default())
+        })
+        .flat_map(|it| it.info.into_iter())
+        .collect();
+    for reference in impl_references {
+        let FileRange { file_id, range } = reference.file_range;
+        if !references
+            .iter()
+            .any(|it| it.file_range.file_id == file_id && it.file_range.range == range)
+        {
+            references.push(reference);
+        }
+    }
+
+    let edit = references
+        .into_iter()
+        // These references don't spell out the renamed name.
+        .filter(|reference| {
+            !matches!(
+                reference.kind,
+                ReferenceKind::SelfType | ReferenceKind::Conversion | ReferenceKind::Implementation
+            )
+        })
+        .map(|reference| match &shorthand_binding {
+            Some((decl, pat))
+                if reference.file_range.file_id == decl.file_id
+                    && reference.file_range.range == decl.range =>
+            {
+                mark::hit!(test_rename_local_bound_by_pat_shorthand);
+                shorthand_binding_edit(reference.file_range.file_id, pat, new_name)
+                    .unwrap_or_else(|| source_edit_from_reference(reference, new_name))
+            }
+            _ => source_edit_from_reference(reference, new_name),
+        })
+        .collect::<Vec<_>>();
+
+    if edit.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(RangeInfo::new(range, SourceChange::source_file_edits(edit))))
+}
+
+fn rename_lifetime(
     db: &RootDatabase,
     position: FilePosition,
     new_name: &str,
-) -> Option<RangeInfo<SourceChange>> {
-    let RangeInfo { range, info: refs } = find_all_refs(db, position, None)?;
+) -> Result<Option<RangeInfo<SourceChange>>, RangeInfo<RenameError>> {
+    let RangeInfo { range, info: refs } =
+        match find_all_refs(db, position, &FindAllRefsConfig::default()) {
+            Some(it) => it,
+            None => return Ok(None),
+        };
+
+    let is_valid = lex_single_valid_syntax_kind(new_name) == Some(SyntaxKind::LIFETIME)
+        && new_name != "'static"
+        && new_name != "'_";
+    if !is_valid {
+        let message = format!("`{}` is not a valid lifetime name", new_name);
+        return Err(RangeInfo::new(range, RenameError { message, conflict: None }));
+    }
 
     let edit = refs
         .into_iter()
         .map(|reference| source_edit_from_reference(reference, new_name))
         .collect::<Vec<_>>();
 
-    if edit.is_empty() {
+    Ok(Some(RangeInfo::new(range, SourceChange::source_file_edits(edit))))
+}
+
+fn find_name_conflict(
+    sema: &Semantics<RootDatabase>,
+    refs: &ReferenceSearchResult,
+    new_name: &str,
+) -> Option<RenameError> {
+    let existing = match refs.definition()? {
+        Definition::Local(local) => local_name_conflict(sema, local, refs, new_name)?,
+        Definition::ModuleDef(def) => module_item_name_conflict(sema, def, new_name)?,
+        _ => return None,
+    };
+    Some(RenameError {
+        message: format!("`{}` is already defined in this scope", new_name),
+        conflict: Some(existing.try_to_nav(sema.db)?),
+    })
+}
+
+/// Looks for another local named `new_name` visible at the declaration or at
+/// any usage of `local`, which would shadow it or be shadowed by it.
+fn local_name_conflict(
+    sema: &Semantics<RootDatabase>,
+    local: Local,
+    refs: &ReferenceSearchResult,
+    new_name: &str,
+) -> Option<Definition> {
+    let decl = &refs.declaration().nav;
+    let decl = FileRange { file_id: decl.file_id(), range: decl.range() };
+    std::iter::once(decl).chain(refs.references().iter().map(|it| it.file_range)).find_map(
+        |frange| {
+            let file = sema.parse(frange.file_id);
+            let node = file.syntax().token_at_offset(frange.range.start()).right_biased()?.parent();
+            let mut conflict = None;
+            sema.scope(&node).process_all_names(&mut |name, def| match def {
+                ScopeDef::Local(it) if it != local && name.to_string() == new_name => {
+                    conflict = Some(it)
+                }
+                _ => (),
+            });
+            conflict.map(Definition::Local)
+        },
+    )
+}
+
+/// Looks for another item named `new_name` in the module declaring `def`.
+fn module_item_name_conflict(
+    sema: &Semantics<RootDatabase>,
+    def: ModuleDef,
+    new_name: &str,
+) -> Option<Definition> {
+    let scope = def.module(sema.db)?.scope(sema.db, None);
+    // Associated items and enum variants are not part of the module scope.
+    if !scope.iter().any(|(_, it)| matches!(it, ScopeDef::ModuleDef(it) if *it == def)) {
         return None;
     }
+    scope.into_iter().find_map(|(name, it)| match it {
+        ScopeDef::ModuleDef(it) if it != def && name.to_string() == new_name => {
+            Some(Definition::ModuleDef(it))
+        }
+        _ => None,
+    })
+}
+
+fn record_pat_shorthand_binding(
+    sema: &Semantics<RootDatabase>,
+    name_range: FileRange,
+) -> Option<ast::BindPat> {
+    let file = sema.parse(name_range.file_id);
+    let name = find_node_at_offset::<ast::Name>(file.syntax(), name_range.range.start())?;
+    if name.syntax().text_range() != name_range.range {
+        return None;
+    }
+    let pat = ast::BindPat::cast(name.syntax().parent()?)?;
+    ast::RecordFieldPatList::cast(pat.syntax().parent()?)?;
+    Some(pat)
+}
 
-    Some(RangeInfo::new(range, SourceChange::source_file_edits(edit)))
+/// Turns the shorthand `mut x` into `x: mut new_name`.
+fn shorthand_binding_edit(
+    file_id: FileId,
+    pat: &ast::BindPat,
+    new_name: &str,
+) -> Option<SourceFileEdit> {
+    let name = pat.name()?;
+    let range = pat.syntax().text_range();
+    let prefix = pat.syntax().text().slice(..name.syntax().text_range().start() - range.start());
+    let replacement = format!("{}: {}{}", name.text(), prefix, new_name);
+    Some(SourceFileEdit { file_id, edit: TextEdit::replace(range, replacement) })
 }
 
 #[cfg(test)]
@@ -264,6 +651,7 @@ mod tests {
 
     use crate::{
         mock_analysis::analysis_and_position, mock_analysis::single_file_with_position, FileId,
+        TextRange,
     };
 
     #[test]
@@ -304,9 +692,28 @@ mod tests {
         let i<|> = 1;
     }",
         );
-        let new_name = "invalid!";
-        let source_change = analysis.rename(position, new_name).unwrap();
-        assert!(source_change.is_none());
+        for new_name in &["invalid!", "", "foo bar", "1foo", "match"] {
+            let err = analysis.rename(position, new_name).unwrap().unwrap_err();
+            assert_eq!(err.info.message, format!("`{}` is not a valid identifier", new_name));
+            assert_eq!(err.range, TextRange::new(29.into(), 30.into()));
+        }
+    }
+
+    #[test]
+    fn test_rename_to_raw_keyword() {
+        test_rename(
+            r#"
+    fn main() {
+        let i<|> = 1;
+        i
+    }"#,
+            "r#match",
+            r#"
+    fn main() {
+        let r#match = 1;
+        r#match
+    }"#,
+        );
     }
 
     #[test]
@@ -620,6 +1027,243 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_rename_struct_field_for_pat_shorthand() {
+        test_rename(
+            r#"
+    struct Foo {
+        i<|>: i32,
+    }
+
+    fn baz(foo: Foo) -> i32 {
+        let Foo { i } = foo;
+        i
+    }
+    "#,
+            "j",
+            r#"
+    struct Foo {
+        j: i32,
+    }
+
+    fn baz(foo: Foo) -> i32 {
+        let Foo { j: i } = foo;
+        i
+    }
+    "#,
+        );
+    }
+
+    #[test]
+    fn test_rename_local_bound_by_pat_shorthand() {
+        mark::check!(test_rename_local_bound_by_pat_shorthand);
+        test_rename(
+            r#"
+    struct Foo {
+        i: i32,
+    }
+
+    fn baz(foo: Foo) -> i32 {
+        let Foo { mut i } = foo;
+        i += 1;
+        i<|>
+    }
+    "#,
+            "j",
+            r#"
+    struct Foo {
+        i: i32,
+    }
+
+    fn baz(foo: Foo) -> i32 {
+        let Foo { i: mut j } = foo;
+        j += 1;
+        j
+    }
+    "#,
+        );
+    }
+
+    #[test]
+    fn test_rename_local_conflict() {
+        let (analysis, position) = single_file_with_position(
+            r#"
+    fn main() -> i32 {
+        let foo<|> = 1;
+        let bar = 2;
+        foo + bar
+    }"#,
+        );
+        let err = analysis.rename(position, "bar").unwrap().unwrap_err();
+        assert_eq!(err.info.message, "`bar` is already defined in this scope");
+        err.info.conflict.unwrap().assert_match("bar BIND_PAT FileId(1) 57..60");
+    }
+
+    #[test]
+    fn test_rename_module_item_conflict() {
+        let (analysis, position) = single_file_with_position(
+            r#"
+    mod foo {
+        pub fn bar<|>() {}
+        pub fn baz() {}
+    }"#,
+        );
+        let err = analysis.rename(position, "baz").unwrap().unwrap_err();
+        err.info.conflict.unwrap().assert_match("baz FN_DEF FileId(1) 47..62 54..57");
+    }
+
+    #[test]
+    fn test_rename_assoc_fn_to_module_item_name() {
+        test_rename(
+            r#"
+    struct Foo;
+    impl Foo {
+        fn bar<|>() {}
+    }
+    fn baz() {
+        Foo::bar();
+    }"#,
+            "baz",
+            r#"
+    struct Foo;
+    impl Foo {
+        fn baz() {}
+    }
+    fn baz() {
+        Foo::baz();
+    }"#,
+        );
+    }
+
+    #[test]
+    fn test_rename_trait_method_renames_impls() {
+        test_rename(
+            r#"
+    trait Greet {
+        fn greet<|>(&self);
+    }
+    struct A;
+    impl Greet for A {
+        fn greet(&self) {}
+    }
+    struct B;
+    impl Greet for B {
+        fn greet(&self) {}
+    }
+    fn f(a: A, b: B) {
+        a.greet();
+        Greet::greet(&b);
+    }"#,
+            "hello",
+            r#"
+    trait Greet {
+        fn hello(&self);
+    }
+    struct A;
+    impl Greet for A {
+        fn hello(&self) {}
+    }
+    struct B;
+    impl Greet for B {
+        fn hello(&self) {}
+    }
+    fn f(a: A, b: B) {
+        a.hello();
+        Greet::hello(&b);
+    }"#,
+        );
+    }
+
+    #[test]
+    fn test_rename_impl_method_renames_trait_method() {
+        mark::check!(test_rename_impl_method_renames_trait_method);
+        test_rename(
+            r#"
+    trait Greet {
+        fn greet(&self);
+    }
+    struct A;
+    impl Greet for A {
+        fn greet<|>(&self) {}
+    }
+    struct B;
+    impl Greet for B {
+        fn greet(&self) {}
+    }
+    fn f(a: A, b: B) {
+        a.greet();
+        Greet::greet(&b);
+    }"#,
+            "hello",
+            r#"
+    trait Greet {
+        fn hello(&self);
+    }
+    struct A;
+    impl Greet for A {
+        fn hello(&self) {}
+    }
+    struct B;
+    impl Greet for B {
+        fn hello(&self) {}
+    }
+    fn f(a: A, b: B) {
+        a.hello();
+        Greet::hello(&b);
+    }"#,
+        );
+    }
+
+    #[test]
+    fn test_rename_lifetime() {
+        test_rename(
+            r#"
+    fn foo<'a<|>>(x: &'a str) -> &'a str {
+        let f = |y: &'a str| -> &'a str { y };
+        fn nested<'a>(z: &'a str) -> &'a str { z }
+        f(x)
+    }"#,
+            "'b",
+            r#"
+    fn foo<'b>(x: &'b str) -> &'b str {
+        let f = |y: &'b str| -> &'b str { y };
+        fn nested<'a>(z: &'a str) -> &'a str { z }
+        f(x)
+    }"#,
+        );
+    }
+
+    #[test]
+    fn test_prepare_rename() {
+        let check = |ra_fixture: &str, expected: Option<(u32, u32)>| {
+            let (analysis, position) = single_file_with_position(ra_fixture);
+            let range = analysis.prepare_rename(position).unwrap();
+            assert_eq!(
+                range,
+                expected.map(|(start, end)| TextRange::new(start.into(), end.into()))
+            );
+        };
+        check("fn foo<|>() {}", Some((3, 6)));
+        check("fn foo() { let dummy = 1; foo<|>(); }", Some((26, 29)));
+        check("fn foo<'a<|>>(x: &'a str) {}", Some((7, 9)));
+        check("struct S; impl S { fn f(&<|>self) {} }", Some((24, 29)));
+        check("use std::fmt as f<|>mt;", Some((16, 19)));
+        check("fn foo() { 1<|>; }", None);
+    }
+
+    #[test]
+    fn test_rename_lifetime_to_invalid_name() {
+        let (analysis, position) = single_file_with_position(
+            r#"
+    fn foo<'a>(x: &'a<|> str) -> &'a str { x }"#,
+        );
+        for new_name in &["b", "'static", "'_"] {
+            let err = analysis.rename(position, new_name).unwrap().unwrap_err();
+            assert_eq!(err.info.message, format!("`{}` is not a valid lifetime name", new_name));
+            assert!(err.info.conflict.is_none());
+        }
+    }
+
     #[test]
     fn test_rename_mod() {
         let (analysis, position) = analysis_and_position(
@@ -635,7 +1279,7 @@ mod tests {
             ",
         );
         let new_name = "foo2";
-        let source_change = analysis.rename(position, new_name).unwrap();
+        let source_change = analysis.rename(position, new_name).unwrap().unwrap();
         assert_debug_snapshot!(&source_change,
 @r###"
         Some(
@@ -686,7 +1330,7 @@ mod tests {
             ",
         );
         let new_name = "foo2";
-        let source_change = analysis.rename(position, new_name).unwrap();
+        let source_change = analysis.rename(position, new_name).unwrap().unwrap();
         assert_debug_snapshot!(&source_change,
         @r###"
         Some(
@@ -727,6 +1371,126 @@ mod tests {
                );
     }
 
+    #[test]
+    fn test_rename_aliased_item_keeps_alias() {
+        test_rename(
+            r#"
+    mod foo {
+        pub struct Bar<|>;
+    }
+    use foo::Bar as Baz;
+
+    fn main() {
+        let _: Baz = Baz;
+        let _ = foo::Bar;
+    }"#,
+            "Quux",
+            r#"
+    mod foo {
+        pub struct Quux;
+    }
+    use foo::Quux as Baz;
+
+    fn main() {
+        let _: Baz = Baz;
+        let _ = foo::Quux;
+    }"#,
+        );
+    }
+
+    #[test]
+    fn test_rename_alias() {
+        test_rename(
+            r#"
+    mod foo {
+        pub struct Bar;
+    }
+    use foo::Bar as Baz<|>;
+
+    fn main() {
+        let _: Baz = Baz;
+        let _ = foo::Bar;
+    }"#,
+            "Quux",
+            r#"
+    mod foo {
+        pub struct Bar;
+    }
+    use foo::Bar as Quux;
+
+    fn main() {
+        let _: Quux = Quux;
+        let _ = foo::Bar;
+    }"#,
+        );
+    }
+
+    #[test]
+    fn test_rename_reexported_alias_in_other_files() {
+        let (analysis, position) = analysis_and_position(
+            r#"
+            //- /lib.rs
+            mod foo;
+            mod bar {
+                pub struct Bar;
+            }
+            pub use bar::Bar as Baz<|>;
+            fn f(_: Baz) {}
+
+            //- /foo.rs
+            use crate::Baz;
+            fn g() -> Baz { Baz }
+            "#,
+        );
+        let source_change = analysis.rename(position, "Quux").unwrap().unwrap().unwrap();
+        let mut texts = Vec::new();
+        for file_id in &[FileId(1), FileId(2)] {
+            let mut builder = TextEditBuilder::default();
+            for edit in source_change.info.source_file_edits.iter() {
+                if edit.file_id == *file_id {
+                    for indel in edit.edit.iter() {
+                        builder.replace(indel.delete, indel.insert.clone());
+                    }
+                }
+            }
+            let mut text = analysis.file_text(*file_id).unwrap().to_string();
+            builder.finish().apply(&mut text);
+            texts.push(text);
+        }
+        assert_eq_text!(
+            "mod foo;\nmod bar {\n    pub struct Bar;\n}\npub use bar::Bar as Quux;\nfn f(_: Quux) {}\n",
+            &*texts[0]
+        );
+        assert_eq_text!("use crate::Quux;\nfn g() -> Quux { Quux }\n", &*texts[1]);
+    }
+
+    #[test]
+    fn test_rename_alias_from_usage() {
+        test_rename(
+            r#"
+    mod foo {
+        pub fn bar() {}
+    }
+    use foo::bar as baz;
+
+    fn main() {
+        baz<|>();
+        foo::bar();
+    }"#,
+            "quux",
+            r#"
+    mod foo {
+        pub fn bar() {}
+    }
+    use foo::bar as quux;
+
+    fn main() {
+        quux();
+        foo::bar();
+    }"#,
+        );
+    }
+
     #[test]
     fn test_module_rename_in_path() {
         test_rename(
@@ -768,7 +1532,7 @@ mod tests {
             ",
         );
         let new_name = "foo2";
-        let source_change = analysis.rename(position, new_name).unwrap();
+        let source_change = analysis.rename(position, new_name).unwrap().unwrap();
         assert_debug_snapshot!(&source_change,
 @r###"
         Some(
@@ -821,6 +1585,145 @@ mod tests {
         "###);
     }
 
+    #[test]
+    fn test_rename_mod_from_path() {
+        let (analysis, position) = analysis_and_position(
+            "
+            //- /lib.rs
+            mod bar;
+            fn f() {
+                bar::fo<|>o::fun()
+            }
+
+            //- /bar.rs
+            pub mod foo;
+
+            //- /bar/foo.rs
+            // pub fn fun() {}
+            ",
+        );
+        let new_name = "foo2";
+        let source_change = analysis.rename(position, new_name).unwrap().unwrap();
+        assert_debug_snapshot!(&source_change,
+@r###"
+        Some(
+            RangeInfo {
+                range: 27..30,
+                info: SourceChange {
+                    source_file_edits: [
+                        SourceFileEdit {
+                            file_id: FileId(
+                                2,
+                            ),
+                            edit: TextEdit {
+                                indels: [
+                                    Indel {
+                                        insert: "foo2",
+                                        delete: 8..11,
+                                    },
+                                ],
+                            },
+                        },
+                        SourceFileEdit {
+                            file_id: FileId(
+                                1,
+                            ),
+                            edit: TextEdit {
+                                indels: [
+                                    Indel {
+                                        insert: "foo2",
+                                        delete: 27..30,
+                                    },
+                                ],
+                            },
+                        },
+                    ],
+                    file_system_edits: [
+                        MoveFile {
+                            src: FileId(
+                                3,
+                            ),
+                            dst_source_root: SourceRootId(
+                                0,
+                            ),
+                            dst_path: "bar/foo2.rs",
+                        },
+                    ],
+                    is_snippet: false,
+                },
+            },
+        )
+        "###);
+    }
+
+    #[test]
+    fn test_rename_mod_in_dir_from_path() {
+        let (analysis, position) = analysis_and_position(
+            "
+            //- /lib.rs
+            mod foo;
+            fn f() {
+                fo<|>o::bar()
+            }
+
+            //- /foo/mod.rs
+            pub fn bar() {}
+            ",
+        );
+        let new_name = "foo2";
+        let source_change = analysis.rename(position, new_name).unwrap().unwrap();
+        assert_debug_snapshot!(&source_change,
+@r###"
+        Some(
+            RangeInfo {
+                range: 22..25,
+                info: SourceChange {
+                    source_file_edits: [
+                        SourceFileEdit {
+                            file_id: FileId(
+                                1,
+                            ),
+                            edit: TextEdit {
+                                indels: [
+                                    Indel {
+                                        insert: "foo2",
+                                        delete: 4..7,
+                                    },
+                                ],
+                            },
+                        },
+                        SourceFileEdit {
+                            file_id: FileId(
+                                1,
+                            ),
+                            edit: TextEdit {
+                                indels: [
+                                    Indel {
+                                        insert: "foo2",
+                                        delete: 22..25,
+                                    },
+                                ],
+                            },
+                        },
+                    ],
+                    file_system_edits: [
+                        MoveFile {
+                            src: FileId(
+                                2,
+                            ),
+                            dst_source_root: SourceRootId(
+                                0,
+                            ),
+                            dst_path: "foo2/mod.rs",
+                        },
+                    ],
+                    is_snippet: false,
+                },
+            },
+        )
+        "###);
+    }
+
     #[test]
     fn test_enum_variant_from_module_1() {
         test_rename(
@@ -974,7 +1877,7 @@ mod tests {
 
     fn test_rename(text: &str, new_name: &str, expected: &str) {
         let (analysis, position) = single_file_with_position(text);
-        let source_change = analysis.rename(position, new_name).unwrap();
+        let source_change = analysis.rename(position, new_name).unwrap().unwrap();
         let mut text_edit_builder = TextEditBuilder::default();
         let mut file_id: Option<FileId> = None;
         if let Some(change) = source_change {
@@ -4,13 +4,23 @@
 //! get a super-set of matches. Then, we we confirm each match using precise
 //! name resolution.
 
-use std::{convert::TryInto, mem};
+use std::{convert::TryInto, iter::successors, mem};
 
-use hir::{DefWithBody, HasSource, Module, ModuleSource, Semantics, Visibility};
+use hir::{
+    original_range, Adt, AsAssocItem, AssocItem, AssocItemContainer, Crate, DefWithBody, Function,
+    HasSource, ImplDef, MacroDef, Module, ModuleDef, ModuleSource, PathResolution, ScopeDef,
+    Semantics, Trait, Type, Visibility,
+};
 use once_cell::unsync::Lazy;
+use ra_cfg::{parse_cfg, CfgExpr};
 use ra_db::{FileId, FileRange, SourceDatabaseExt};
 use ra_prof::profile;
-use ra_syntax::{ast, match_ast, AstNode, TextRange, TextSize};
+use ra_syntax::{
+    algo::find_node_at_offset,
+    ast::{self, AttrsOwner, NameOwner},
+    match_ast, AstNode, NodeOrToken, SmolStr, SyntaxKind, SyntaxNode, SyntaxToken, TextRange,
+    TextSize, T,
+};
 use rustc_hash::FxHashMap;
 
 use crate::{
@@ -23,17 +33,44 @@ pub struct Reference {
     pub file_range: FileRange,
     pub kind: ReferenceKind,
     pub access: Option<ReferenceAccess>,
+    /// Range of the innermost block containing a reference to a local.
+    pub block_scope: Option<TextRange>,
+    /// Whether the reference is inside `#[test]` code or code which is only
+    /// compiled with `cfg(test)`.
+    pub in_test: bool,
+    /// The `fn`, `impl`, `const` or `static` the reference appears in. Only
+    /// computed on request.
+    pub enclosing: Option<EnclosingItem>,
+}
+
+/// An item containing a reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnclosingItem {
+    pub name: SmolStr,
+    pub kind: SyntaxKind,
+    pub range: TextRange,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ReferenceKind {
     FieldShorthandForField,
     FieldShorthandForLocal,
     StructLiteral,
+    /// A path in a pattern, like `Foo::Bar` in `Foo::Bar(x) => ...`.
+    Pattern,
+    Attribute,
+    Conversion,
+    Implementation,
+    /// `Self` inside an impl of the searched type.
+    SelfType,
+    /// A trait method and its implementations in `impl Trait for X` blocks.
+    TraitImplItem,
+    /// The path of a macro call, like `m` in `m!()`.
+    MacroCall,
     Other,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum ReferenceAccess {
     Read,
     Write,
@@ -43,6 +80,7 @@ pub enum ReferenceAccess {
 /// For `pub(crate)` things it's a crate, for `pub` things it's a crate and dependant crates.
 /// In some cases, the location of the references is known to within a `TextRange`,
 /// e.g. for things like local variables.
+#[derive(Debug, Clone)]
 pub struct SearchScope {
     entries: FxHashMap<FileId, Option<TextRange>>,
 }
@@ -60,6 +98,47 @@ impl SearchScope {
         SearchScope::new(std::iter::once((file, None)).collect())
     }
 
+    pub fn files(files: &[FileId]) -> SearchScope {
+        SearchScope::new(files.iter().map(|file| (*file, None)).collect())
+    }
+
+    /// Restricts the search to `range` of `file`, which is expected to be the
+    /// body owning the searched local.
+    pub fn function_body(file: FileId, range: TextRange) -> SearchScope {
+        SearchScope::new(std::iter::once((file, Some(range))).collect())
+    }
+
+    /// The files, or parts of a file for inline modules, making up `module`
+    /// and all of its descendants.
+    pub fn module_subtree(db: &RootDatabase, module: Module) -> SearchScope {
+        let mut res = FxHashMap::default();
+
+        let mut to_visit = vec![module];
+        let mut is_first = true;
+        while let Some(module) = to_visit.pop() {
+            let src = module.definition_source(db);
+            let file_id = src.file_id.original_file(db);
+            match src.value {
+                ModuleSource::Module(m) => {
+                    if is_first {
+                        let range = Some(m.syntax().text_range());
+                        res.insert(file_id, range);
+                    } else {
+                        // We have already added the enclosing file to the search scope,
+                        // so do nothing.
+                    }
+                }
+                ModuleSource::SourceFile(_) => {
+                    res.insert(file_id, None);
+                }
+            };
+            is_first = false;
+            to_visit.extend(module.children(db));
+        }
+
+        SearchScope::new(res)
+    }
+
     pub fn intersection(&self, other: &SearchScope) -> SearchScope {
         let (mut small, mut large) = (&self.entries, &other.entries);
         if small.len() > large.len() {
@@ -90,6 +169,31 @@ impl SearchScope {
             }
         }
     }
+
+    /// Two ranges in the same file are joined into the range covering both.
+    pub fn union(&self, other: &SearchScope) -> SearchScope {
+        let mut res = self.entries.clone();
+        for (file_id, r2) in other.entries.iter() {
+            let r = match res.get(file_id) {
+                None => *r2,
+                Some(None) => None,
+                Some(Some(r1)) => r2.map(|r2| r1.cover(r2)),
+            };
+            res.insert(*file_id, r);
+        }
+        SearchScope::new(res)
+    }
+}
+
+impl SearchScope {
+    /// Whether `frange` lies within the scope.
+    pub fn contains(&self, frange: FileRange) -> bool {
+        match self.entries.get(&frange.file_id) {
+            None => false,
+            Some(None) => true,
+            Some(Some(range)) => range.contains_range(frange.range),
+        }
+    }
 }
 
 impl IntoIterator for SearchScope {
@@ -102,7 +206,9 @@ impl IntoIterator for SearchScope {
 }
 
 impl Definition {
-    fn search_scope(&self, db: &RootDatabase) -> SearchScope {
+    /// The files, or parts of them, which may contain references to the
+    /// definition. Locals are only searched in the body owning them.
+    pub fn search_scope(&self, db: &RootDatabase) -> SearchScope {
         let _p = profile("search_scope");
         let module = match self.module(db) {
             Some(it) => it,
@@ -113,45 +219,56 @@ impl Definition {
 
         if let Definition::Local(var) = self {
             let range = match var.parent(db) {
-                DefWithBody::Function(f) => f.source(db).value.syntax().text_range(),
-                DefWithBody::Const(c) => c.source(db).value.syntax().text_range(),
-                DefWithBody::Static(s) => s.source(db).value.syntax().text_range(),
+                DefWithBody::Function(f) => {
+                    let fn_def = f.source(db).value;
+                    // Parameters are declared outside of the body, but are only
+                    // referenced inside of it.
+                    match fn_def.body() {
+                        Some(body) => body.syntax().text_range(),
+                        None => fn_def.syntax().text_range(),
+                    }
+                }
+                DefWithBody::Const(c) => {
+                    let const_def = c.source(db).value;
+                    match const_def.body() {
+                        Some(body) => body.syntax().text_range(),
+                        None => const_def.syntax().text_range(),
+                    }
+                }
+                DefWithBody::Static(s) => {
+                    let static_def = s.source(db).value;
+                    match static_def.body() {
+                        Some(body) => body.syntax().text_range(),
+                        None => static_def.syntax().text_range(),
+                    }
+                }
             };
-            let mut res = FxHashMap::default();
-            res.insert(file_id, Some(range));
+            return SearchScope::function_body(file_id, range);
+        }
+
+        // `macro_rules!` macros are textually scoped and can escape their module
+        // via `#[macro_use]`, so search the whole crate, and its dependants
+        // for `#[macro_export]` ones.
+        if let Definition::Macro(mac) = self {
+            let source_root_id = db.file_source_root(file_id);
+            let source_root = db.source_root(source_root_id);
+            let mut res = source_root.walk().map(|id| (id, None)).collect::<FxHashMap<_, _>>();
+
+            if is_macro_exported(db, *mac) {
+                for rev_dep in module.krate().reverse_dependencies(db) {
+                    let root_file = rev_dep.root_file(db);
+                    let source_root_id = db.file_source_root(root_file);
+                    let source_root = db.source_root(source_root_id);
+                    res.extend(source_root.walk().map(|id| (id, None)));
+                }
+            }
             return SearchScope::new(res);
         }
 
         let vis = self.visibility(db);
 
         if let Some(Visibility::Module(module)) = vis.and_then(|it| it.into()) {
-            let module: Module = module.into();
-            let mut res = FxHashMap::default();
-
-            let mut to_visit = vec![module];
-            let mut is_first = true;
-            while let Some(module) = to_visit.pop() {
-                let src = module.definition_source(db);
-                let file_id = src.file_id.original_file(db);
-                match src.value {
-                    ModuleSource::Module(m) => {
-                        if is_first {
-                            let range = Some(m.syntax().text_range());
-                            res.insert(file_id, range);
-                        } else {
-                            // We have already added the enclosing file to the search scope,
-                            // so do nothing.
-                        }
-                    }
-                    ModuleSource::SourceFile(_) => {
-                        res.insert(file_id, None);
-                    }
-                };
-                is_first = false;
-                to_visit.extend(module.children(db));
-            }
-
-            return SearchScope::new(res);
+            return SearchScope::module_subtree(db, module.into());
         }
 
         if let Some(Visibility::Public) = vis {
@@ -183,6 +300,22 @@ impl Definition {
         db: &RootDatabase,
         search_scope: Option<SearchScope>,
     ) -> Vec<Reference> {
+        let mut refs = Vec::new();
+        self.find_usages_with(db, search_scope, &mut |reference| {
+            refs.push(reference);
+            true
+        });
+        refs
+    }
+
+    /// Same as `find_usages`, but passes each reference to `sink` as soon as
+    /// it is found. The search stops once `sink` returns `false`.
+    pub fn find_usages_with(
+        &self,
+        db: &RootDatabase,
+        search_scope: Option<SearchScope>,
+        sink: &mut dyn FnMut(Reference) -> bool,
+    ) {
         let _p = profile("Definition::find_usages");
 
         let search_scope = {
@@ -194,12 +327,40 @@ impl Definition {
         };
 
         let name = match self.name(db) {
-            None => return Vec::new(),
+            None => return,
             Some(it) => it.to_string(),
         };
 
         let pat = name.as_str();
-        let mut refs = vec![];
+
+        // `impl From<T> for U` is also used by `t.into()` calls.
+        let conversion = match self {
+            Definition::ModuleDef(ModuleDef::Function(func)) => {
+                from_impl_types(&Semantics::new(db), *func)
+            }
+            _ => None,
+        };
+
+        // Types are also referred to as `Self` inside their impls.
+        let self_type = match self {
+            Definition::ModuleDef(ModuleDef::Adt(adt)) => Some(*adt),
+            _ => None,
+        };
+
+        if let Definition::ModuleDef(ModuleDef::Trait(trait_)) = self {
+            for reference in macro_generated_impls(db, *trait_, &search_scope) {
+                if !sink(reference) {
+                    return;
+                }
+            }
+        }
+        if let Definition::ModuleDef(ModuleDef::Function(func)) = self {
+            for reference in trait_impl_items(db, *func, &search_scope) {
+                if !sink(reference) {
+                    return;
+                }
+            }
+        }
 
         for (file_id, search_range) in search_scope {
             let text = db.file_text(file_id);
@@ -209,71 +370,149 @@ impl Definition {
             let sema = Semantics::new(db);
             let tree = Lazy::new(|| sema.parse(file_id).syntax().clone());
 
+            if let Some((from_ty, to_ty)) = &conversion {
+                for (idx, _) in text.match_indices("into") {
+                    let offset: TextSize = idx.try_into().unwrap();
+                    if !search_range.contains_inclusive(offset) {
+                        continue;
+                    }
+                    let name_ref: Option<ast::NameRef> =
+                        sema.find_node_at_offset_with_descend(&tree, offset);
+                    if let Some(reference) = name_ref
+                        .and_then(|it| into_conversion_reference(&sema, &it, from_ty, to_ty))
+                    {
+                        if !sink(reference) {
+                            return;
+                        }
+                    }
+                }
+            }
+
+            if let Some(adt) = self_type {
+                for (idx, _) in text.match_indices("Self") {
+                    let offset: TextSize = idx.try_into().unwrap();
+                    if !search_range.contains_inclusive(offset) {
+                        continue;
+                    }
+                    let name_ref: Option<ast::NameRef> =
+                        sema.find_node_at_offset_with_descend(&tree, offset);
+                    if let Some(reference) =
+                        name_ref.and_then(|it| self_type_reference(&sema, &it, adt))
+                    {
+                        if !sink(reference) {
+                            return;
+                        }
+                    }
+                }
+            }
+
             for (idx, _) in text.match_indices(pat) {
                 let offset: TextSize = idx.try_into().unwrap();
                 if !search_range.contains_inclusive(offset) {
                     continue;
                 }
 
-                let name_ref: ast::NameRef =
-                    if let Some(name_ref) = sema.find_node_at_offset_with_descend(&tree, offset) {
-                        name_ref
-                    } else {
-                        continue;
+                let name_ref: ast::NameRef = if let Some(name_ref) =
+                    sema.find_node_at_offset_with_descend(&tree, offset)
+                {
+                    name_ref
+                } else {
+                    let name: Option<ast::Name> =
+                        sema.find_node_at_offset_with_descend(&tree, offset);
+                    let reference = match name {
+                        Some(name) => record_pat_shorthand_reference(&sema, self, &name),
+                        None => self_param_reference(&sema, self, &tree, file_id, offset).or_else(
+                            || derive_helper_attr_reference(&sema, self, &tree, file_id, offset),
+                        ),
                     };
+                    if let Some(reference) = reference {
+                        if !sink(reference) {
+                            return;
+                        }
+                    }
+                    continue;
+                };
 
                 // FIXME: reuse sb
                 // See https://github.com/rust-lang/rust/pull/68198#issuecomment-574269098
 
-                match classify_name_ref(&sema, &name_ref) {
+                // Name refs found by descending into a macro call can't always
+                // be mapped back precisely, while the text match is a real
+                // source span.
+                let file_range = if name_ref.syntax().ancestors().last().as_ref() == Some(&*tree) {
+                    sema.original_range(name_ref.syntax())
+                } else {
+                    FileRange { file_id, range: TextRange::at(offset, TextSize::of(pat)) }
+                };
+
+                let reference = match classify_name_ref(&sema, &name_ref) {
                     Some(NameRefClass::Definition(def)) if &def == self => {
-                        let kind = if is_record_lit_name_ref(&name_ref)
+                        let kind = if let Definition::Macro(_) = def {
+                            ReferenceKind::MacroCall
+                        } else if is_record_lit_name_ref(&name_ref)
                             || is_call_expr_name_ref(&name_ref)
                         {
                             ReferenceKind::StructLiteral
+                        } else if is_pat_name_ref(&name_ref) {
+                            ReferenceKind::Pattern
                         } else {
                             ReferenceKind::Other
                         };
 
-                        let file_range = sema.original_range(name_ref.syntax());
-                        refs.push(Reference {
+                        Reference {
                             file_range,
                             kind,
-                            access: reference_access(&def, &name_ref),
-                        });
-                    }
-                    Some(NameRefClass::FieldShorthand { local, field }) => {
-                        match self {
-                            Definition::Field(_) if &field == self => refs.push(Reference {
-                                file_range: sema.original_range(name_ref.syntax()),
-                                kind: ReferenceKind::FieldShorthandForField,
-                                access: reference_access(&field, &name_ref),
-                            }),
-                            Definition::Local(l) if &local == l => refs.push(Reference {
-                                file_range: sema.original_range(name_ref.syntax()),
-                                kind: ReferenceKind::FieldShorthandForLocal,
-                                access: reference_access(&Definition::Local(local), &name_ref),
-                            }),
-
-                            _ => {} // not a usage
-                        };
+                            access: reference_access(&sema, &def, name_ref.syntax()),
+                            block_scope: block_scope(&sema, &def, name_ref.syntax()),
+                            in_test: is_in_test(&sema, name_ref.syntax()),
+                            enclosing: None,
+                        }
                     }
-                    _ => {} // not a usage
+                    Some(NameRefClass::FieldShorthand { local, field }) => match self {
+                        Definition::Field(_) if &field == self => Reference {
+                            file_range,
+                            kind: ReferenceKind::FieldShorthandForField,
+                            access: reference_access(&sema, &field, name_ref.syntax()),
+                            block_scope: None,
+                            in_test: is_in_test(&sema, name_ref.syntax()),
+                            enclosing: None,
+                        },
+                        Definition::Local(l) if &local == l => Reference {
+                            file_range,
+                            kind: ReferenceKind::FieldShorthandForLocal,
+                            access: reference_access(
+                                &sema,
+                                &Definition::Local(local),
+                                name_ref.syntax(),
+                            ),
+                            block_scope: block_scope(&sema, self, name_ref.syntax()),
+                            in_test: is_in_test(&sema, name_ref.syntax()),
+                            enclosing: None,
+                        },
+                        _ => continue, // not a usage
+                    },
+                    _ => continue, // not a usage
+                };
+                if !sink(reference) {
+                    return;
                 }
             }
         }
-        refs
     }
 }
 
-fn reference_access(def: &Definition, name_ref: &ast::NameRef) -> Option<ReferenceAccess> {
+fn reference_access(
+    sema: &Semantics<RootDatabase>,
+    def: &Definition,
+    syntax: &SyntaxNode,
+) -> Option<ReferenceAccess> {
     // Only Locals and Fields have accesses for now.
     match def {
         Definition::Local(_) | Definition::Field(_) => {}
         _ => return None,
     };
 
-    let mode = name_ref.syntax().ancestors().find_map(|node| {
+    let mode = syntax.ancestors().find_map(|node| {
         match_ast! {
             match (node) {
                 ast::BinExpr(expr) => {
@@ -281,13 +520,28 @@ fn reference_access(def: &Definition, name_ref: &ast::NameRef) -> Option<Referen
                         // If the variable or field ends on the LHS's end then it's a Write (covers fields and locals).
                         // FIXME: This is not terribly accurate.
                         if let Some(lhs) = expr.lhs() {
-                            if lhs.syntax().text_range().end() == name_ref.syntax().text_range().end() {
+                            if lhs.syntax().text_range().end() == syntax.text_range().end() {
                                 return Some(ReferenceAccess::Write);
                             }
                         }
                     }
                     Some(ReferenceAccess::Read)
                 },
+                ast::MethodCallExpr(call) => {
+                    // The receiver of a `&mut self` method is written to.
+                    let receiver = call.expr()?;
+                    if receiver.syntax().text_range().end() != syntax.text_range().end() {
+                        return None;
+                    }
+                    let self_param = sema
+                        .resolve_method_call(&call)
+                        .and_then(|func| func.source(sema.db).value.param_list())
+                        .and_then(|params| params.self_param());
+                    match self_param.map(|it| it.kind()) {
+                        Some(ast::SelfParamKind::MutRef) => Some(ReferenceAccess::Write),
+                        _ => Some(ReferenceAccess::Read),
+                    }
+                },
                 _ => None
             }
         }
@@ -297,6 +551,417 @@ fn reference_access(def: &Definition, name_ref: &ast::NameRef) -> Option<Referen
     mode.or(Some(ReferenceAccess::Read))
 }
 
+fn block_scope(
+    sema: &Semantics<RootDatabase>,
+    def: &Definition,
+    node: &SyntaxNode,
+) -> Option<TextRange> {
+    // Only Locals live in blocks.
+    match def {
+        Definition::Local(_) => {}
+        _ => return None,
+    };
+    let block = node.ancestors().find_map(ast::BlockExpr::cast)?;
+    Some(sema.original_range(block.syntax()).range)
+}
+
+fn is_in_test(sema: &Semantics<RootDatabase>, node: &SyntaxNode) -> bool {
+    sema.ancestors_with_macros(node.clone()).any(|node| {
+        node.children().filter_map(ast::Attr::cast).any(|attr| match attr.as_simple_call() {
+            Some((name, tt)) => name == "cfg" && cfg_requires_test(&tt),
+            None => attr.as_simple_atom().as_deref() == Some("test"),
+        })
+    })
+}
+
+/// Checks whether the condition of a `cfg` attribute only holds with `test`
+/// enabled, like `#[cfg(test)]` or `#[cfg(all(test, unix))]`.
+fn cfg_requires_test(tt: &ast::TokenTree) -> bool {
+    fn requires_test(cfg: &CfgExpr) -> bool {
+        match cfg {
+            CfgExpr::Atom(name) => name == "test",
+            CfgExpr::All(preds) => preds.iter().any(requires_test),
+            CfgExpr::Any(preds) => !preds.is_empty() && preds.iter().all(requires_test),
+            CfgExpr::Invalid | CfgExpr::KeyValue { .. } | CfgExpr::Not(_) => false,
+        }
+    }
+    match mbe::ast_to_token_tree(tt) {
+        Some((tt, _)) => requires_test(&parse_cfg(&tt)),
+        None => false,
+    }
+}
+
+/// Impls of `trait_` generated by derives or other macros don't mention the
+/// trait in the source, so they are reported at the macro call site.
+fn macro_generated_impls(db: &RootDatabase, trait_: Trait, scope: &SearchScope) -> Vec<Reference> {
+    let sema = Semantics::new(db);
+    let krate = trait_.module(db).krate();
+    let mut crates = vec![krate];
+    crates.extend(krate.reverse_dependencies(db));
+    crates
+        .into_iter()
+        .flat_map(|krate| ImplDef::for_trait(db, krate, trait_))
+        .filter_map(|impl_def| {
+            let file_range = match impl_def.is_builtin_derive(db) {
+                Some(attr) => original_range(db, attr.as_ref().map(|it| it.syntax())),
+                None => {
+                    let call = impl_def.source(db).file_id.call_node(db)?;
+                    original_range(db, call.as_ref())
+                }
+            };
+            if !scope.contains(file_range) {
+                return None;
+            }
+            Some(Reference {
+                file_range,
+                kind: ReferenceKind::Implementation,
+                access: None,
+                block_scope: None,
+                in_test: is_range_in_test(&sema, file_range),
+                enclosing: None,
+            })
+        })
+        .collect()
+}
+
+/// A trait method is implemented by the methods with the same name in
+/// `impl Trait for X` blocks.
+fn trait_impl_items(db: &RootDatabase, func: Function, scope: &SearchScope) -> Vec<Reference> {
+    let sema = Semantics::new(db);
+    let name = func.name(db);
+    let trait_ = match func.as_assoc_item(db).map(|it| it.container(db)) {
+        Some(AssocItemContainer::Trait(it)) => it,
+        _ => return Vec::new(),
+    };
+    let krate = trait_.module(db).krate();
+    let mut crates = vec![krate];
+    crates.extend(krate.reverse_dependencies(db));
+    crates
+        .into_iter()
+        .flat_map(|krate| ImplDef::for_trait(db, krate, trait_))
+        .flat_map(|impl_def| impl_def.items(db))
+        .filter_map(|item| match item {
+            AssocItem::Function(it) if it.name(db) == name => Some(it),
+            _ => None,
+        })
+        .filter_map(|func| trait_item_reference(&sema, func))
+        .filter(|reference| scope.contains(reference.file_range))
+        .collect()
+}
+
+/// If `func` is a method in an `impl Trait for X` block, returns the
+/// declaration of the method it implements in `Trait`.
+pub fn implemented_trait_item(db: &RootDatabase, func: Function) -> Option<Reference> {
+    let sema = Semantics::new(db);
+    let impl_def = match func.as_assoc_item(db)?.container(db) {
+        AssocItemContainer::ImplDef(it) => it,
+        AssocItemContainer::Trait(_) => return None,
+    };
+    let name = func.name(db);
+    let trait_func =
+        impl_trait(&sema, impl_def)?.items(db).into_iter().find_map(|item| match item {
+            AssocItem::Function(it) if it.name(db) == name => Some(it),
+            _ => None,
+        })?;
+    trait_item_reference(&sema, trait_func)
+}
+
+fn trait_item_reference(sema: &Semantics<RootDatabase>, func: Function) -> Option<Reference> {
+    let src = func.source(sema.db);
+    let name = src.value.name()?;
+    let file_range = original_range(sema.db, src.with_value(name.syntax()));
+    Some(Reference {
+        file_range,
+        kind: ReferenceKind::TraitImplItem,
+        access: None,
+        block_scope: None,
+        in_test: is_range_in_test(sema, file_range),
+        enclosing: None,
+    })
+}
+
+/// Resolves the trait implemented by `impl_def`.
+fn impl_trait(sema: &Semantics<RootDatabase>, impl_def: ImplDef) -> Option<Trait> {
+    let src = impl_def.source(sema.db);
+    let range = src.value.syntax().text_range();
+    let root = sema.parse(src.file_id.original_file(sema.db));
+    let impl_def = find_node_at_offset::<ast::ImplDef>(root.syntax(), range.start())?;
+    // Bail out on impls generated by macros.
+    if impl_def.syntax().text_range() != range {
+        return None;
+    }
+    let path = match impl_def.target_trait()? {
+        ast::TypeRef::PathType(it) => it.path()?,
+        _ => return None,
+    };
+    match sema.resolve_path(&path)? {
+        PathResolution::Def(ModuleDef::Trait(it)) => Some(it),
+        _ => None,
+    }
+}
+
+fn is_range_in_test(sema: &Semantics<RootDatabase>, file_range: FileRange) -> bool {
+    let root = sema.parse(file_range.file_id);
+    let node = match root.syntax().covering_element(file_range.range) {
+        NodeOrToken::Node(it) => it,
+        NodeOrToken::Token(it) => it.parent(),
+    };
+    is_in_test(sema, &node)
+}
+
+/// If `func` is `from` in an `impl core::convert::From<T> for U`, returns `T`
+/// and `U`.
+fn from_impl_types(sema: &Semantics<RootDatabase>, func: Function) -> Option<(Type, Type)> {
+    let db = sema.db;
+    if func.name(db).to_string() != "from" {
+        return None;
+    }
+    let impl_def = match func.as_assoc_item(db)?.container(db) {
+        AssocItemContainer::ImplDef(it) => it,
+        AssocItemContainer::Trait(_) => return None,
+    };
+    let from_trait = core_convert_trait(db, impl_def.module(db).krate(), "From")?;
+    if impl_trait(sema, impl_def)? != from_trait {
+        return None;
+    }
+
+    let src = func.source(db);
+    let range = src.value.syntax().text_range();
+    let root = sema.parse(src.file_id.original_file(db));
+    let fn_def = find_node_at_offset::<ast::FnDef>(root.syntax(), range.start())?;
+    // Bail out on functions generated by macros.
+    if fn_def.syntax().text_range() != range {
+        return None;
+    }
+    let pat = fn_def.param_list()?.params().next()?.pat()?;
+    Some((sema.type_of_pat(&pat)?, impl_def.target_ty(db)))
+}
+
+/// Checks whether `name_ref` is a `core::convert::Into::into` call converting
+/// `from_ty` into `to_ty`.
+fn into_conversion_reference(
+    sema: &Semantics<RootDatabase>,
+    name_ref: &ast::NameRef,
+    from_ty: &Type,
+    to_ty: &Type,
+) -> Option<Reference> {
+    let call = name_ref.syntax().parent().and_then(ast::MethodCallExpr::cast)?;
+    let method = sema.resolve_method_call(&call)?;
+    let into_trait =
+        core_convert_trait(sema.db, sema.scope(call.syntax()).module()?.krate(), "Into")?;
+    let method_trait = match method.as_assoc_item(sema.db)?.container(sema.db) {
+        AssocItemContainer::Trait(it) => it,
+        AssocItemContainer::ImplDef(it) => impl_trait(sema, it)?,
+    };
+    if method_trait != into_trait {
+        return None;
+    }
+    if &sema.type_of_expr(&call.expr()?)? != from_ty
+        || &sema.type_of_expr(&call.clone().into())? != to_ty
+    {
+        return None;
+    }
+    Some(Reference {
+        file_range: sema.original_range(name_ref.syntax()),
+        kind: ReferenceKind::Conversion,
+        access: None,
+        block_scope: None,
+        in_test: is_in_test(sema, name_ref.syntax()),
+        enclosing: None,
+    })
+}
+
+/// Resolves the trait `core::convert::{name}` as seen from `krate`.
+fn core_convert_trait(db: &RootDatabase, krate: Crate, name: &str) -> Option<Trait> {
+    let core = if krate.display_name(db).map_or(false, |it| it.to_string() == "core") {
+        krate
+    } else {
+        krate.dependencies(db).into_iter().find(|dep| dep.name.to_string() == "core")?.krate
+    };
+    let convert = core
+        .root_module(db)?
+        .children(db)
+        .find(|module| module.name(db).map_or(false, |it| it.to_string() == "convert"))?;
+    convert.scope(db, None).into_iter().find_map(|(it, def)| match def {
+        ScopeDef::ModuleDef(ModuleDef::Trait(trait_)) if it.to_string() == name => Some(trait_),
+        _ => None,
+    })
+}
+
+/// Checks whether `name_ref` is a `Self` which refers to `adt`.
+fn self_type_reference(
+    sema: &Semantics<RootDatabase>,
+    name_ref: &ast::NameRef,
+    adt: Adt,
+) -> Option<Reference> {
+    let impl_def = match classify_name_ref(sema, name_ref)? {
+        NameRefClass::Definition(Definition::SelfType(it)) => it,
+        _ => return None,
+    };
+    if impl_def.target_ty(sema.db).as_adt()? != adt {
+        return None;
+    }
+    Some(Reference {
+        file_range: sema.original_range(name_ref.syntax()),
+        kind: ReferenceKind::SelfType,
+        access: None,
+        block_scope: None,
+        in_test: is_in_test(sema, name_ref.syntax()),
+        enclosing: None,
+    })
+}
+
+/// Field shorthands in record patterns, like `x` in `let Foo { x, .. } = foo;`,
+/// are `ast::Name`s rather than `ast::NameRef`s, so they need special handling.
+fn record_pat_shorthand_reference(
+    sema: &Semantics<RootDatabase>,
+    def: &Definition,
+    name: &ast::Name,
+) -> Option<Reference> {
+    match def {
+        Definition::Field(_) => {}
+        _ => return None,
+    };
+    let bind_pat = name.syntax().parent().and_then(ast::BindPat::cast)?;
+    ast::RecordFieldPatList::cast(bind_pat.syntax().parent()?)?;
+    let field = sema.resolve_record_field_pat_shorthand(&bind_pat)?;
+    if &Definition::Field(field) != def {
+        return None;
+    }
+    Some(Reference {
+        file_range: sema.original_range(name.syntax()),
+        kind: ReferenceKind::FieldShorthandForField,
+        access: Some(ReferenceAccess::Read),
+        block_scope: None,
+        in_test: is_in_test(sema, name.syntax()),
+        enclosing: None,
+    })
+}
+
+/// Attributes that are never derive helpers, even when the item has a `#[derive]`.
+const BUILTIN_ATTRS: &[&str] =
+    &["derive", "cfg", "cfg_attr", "doc", "allow", "warn", "deny", "forbid", "repr"];
+
+/// Arguments of derive helper attributes are plain token trees, so a path like
+/// `crate::helper` in `#[serde(with = crate::helper)]` has no `ast::NameRef`.
+/// We rebuild the path from the tokens and resolve it in the scope of the item.
+fn derive_helper_attr_reference(
+    sema: &Semantics<RootDatabase>,
+    def: &Definition,
+    root: &SyntaxNode,
+    file_id: FileId,
+    offset: TextSize,
+) -> Option<Reference> {
+    let token = root.token_at_offset(offset).right_biased()?;
+    if token.kind() != SyntaxKind::IDENT || token.text_range().start() != offset {
+        return None;
+    }
+    let token_tree = token.parent().ancestors().find_map(ast::TokenTree::cast)?;
+    let attr = token_tree.syntax().ancestors().find_map(ast::Attr::cast)?;
+    if BUILTIN_ATTRS.contains(&attr.simple_name()?.as_str()) {
+        return None;
+    }
+    let has_derive = attr
+        .syntax()
+        .parent()?
+        .children()
+        .filter_map(ast::Attr::cast)
+        .any(|it| it.simple_name().as_deref() == Some("derive"));
+    if !has_derive {
+        return None;
+    }
+
+    let path = helper_attr_path_text(&token)?;
+    let path = ast::SourceFile::parse(&format!("use {};", path))
+        .tree()
+        .syntax()
+        .descendants()
+        .find_map(ast::Path::cast)?;
+    let path = hir::Path::from_ast(path)?;
+    let resolved = sema.scope(attr.syntax()).resolve_hir_path(&path)?;
+    if &Definition::from(resolved) != def {
+        return None;
+    }
+    Some(Reference {
+        file_range: FileRange { file_id, range: token.text_range() },
+        kind: ReferenceKind::Attribute,
+        access: None,
+        block_scope: None,
+        in_test: is_in_test(sema, &token.parent()),
+        enclosing: None,
+    })
+}
+
+/// Collects the path ending at `token`, e.g. `crate::helper` for `helper`.
+/// Inside token trees `::` may be either a single token or two `:` tokens.
+fn helper_attr_path_text(token: &SyntaxToken) -> Option<String> {
+    let mut preceding = successors(token.prev_sibling_or_token(), |it| it.prev_sibling_or_token())
+        .map(|it| it.into_token());
+    let mut text = token.text().to_string();
+    loop {
+        match preceding.next().flatten() {
+            Some(sep) if sep.kind() == T![::] => {}
+            Some(sep) if sep.kind() == T![:] => match preceding.next().flatten() {
+                Some(sep) if sep.kind() == T![:] => {}
+                _ => break,
+            },
+            _ => break,
+        }
+        match preceding.next().flatten() {
+            Some(segment)
+                if matches!(
+                    segment.kind(),
+                    SyntaxKind::IDENT | T![crate] | T![self] | T![super]
+                ) =>
+            {
+                text = format!("{}::{}", segment.text(), text);
+            }
+            _ => return None,
+        }
+    }
+    Some(text)
+}
+
+/// `self` is a keyword rather than a `NameRef`, so uses of the `self`
+/// parameter are found by resolving the path the keyword forms.
+fn self_param_reference(
+    sema: &Semantics<RootDatabase>,
+    def: &Definition,
+    root: &SyntaxNode,
+    file_id: FileId,
+    offset: TextSize,
+) -> Option<Reference> {
+    match def {
+        Definition::Local(local) if local.is_self(sema.db) => {}
+        _ => return None,
+    }
+    let token = root.token_at_offset(offset).right_biased()?;
+    if token.kind() != T![self] || token.text_range().start() != offset {
+        return None;
+    }
+    let path = token.parent().parent().and_then(ast::Path::cast)?;
+    match sema.resolve_path(&path)? {
+        PathResolution::Local(local) if &Definition::Local(local) == def => {}
+        _ => return None,
+    }
+    Some(Reference {
+        file_range: FileRange { file_id, range: token.text_range() },
+        kind: ReferenceKind::Other,
+        access: reference_access(sema, def, path.syntax()),
+        block_scope: block_scope(sema, def, path.syntax()),
+        in_test: is_in_test(sema, path.syntax()),
+        enclosing: None,
+    })
+}
+
+fn is_macro_exported(db: &RootDatabase, mac: MacroDef) -> bool {
+    // Proc-macros have no source, and are always usable from dependants.
+    if mac.is_proc_macro() {
+        return true;
+    }
+    mac.source(db).value.attrs().any(|attr| attr.simple_name().as_deref() == Some("macro_export"))
+}
+
 fn is_call_expr_name_ref(name_ref: &ast::NameRef) -> bool {
     name_ref
         .syntax()
@@ -321,3 +986,19 @@ fn is_record_lit_name_ref(name_ref: &ast::NameRef) -> bool {
         .map(|p| p.name_ref().as_ref() == Some(name_ref))
         .unwrap_or(false)
 }
+
+fn is_pat_name_ref(name_ref: &ast::NameRef) -> bool {
+    name_ref
+        .syntax()
+        .ancestors()
+        .find_map(ast::Path::cast)
+        .filter(|p| p.segment().and_then(|s| s.name_ref()).as_ref() == Some(name_ref))
+        .and_then(|p| p.syntax().parent())
+        .map(|it| {
+            matches!(
+                it.kind(),
+                SyntaxKind::PATH_PAT | SyntaxKind::TUPLE_STRUCT_PAT | SyntaxKind::RECORD_PAT
+            )
+        })
+        .unwrap_or(false)
+}
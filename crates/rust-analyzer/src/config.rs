@@ -34,6 +34,7 @@ pub struct Config {
     pub completion: CompletionConfig,
     pub assist: AssistConfig,
     pub call_info_full: bool,
+    pub references_exclude_tests: bool,
     pub lens: LensConfig,
 }
 
@@ -140,6 +141,7 @@ impl Default for Config {
             },
             assist: AssistConfig::default(),
             call_info_full: true,
+            references_exclude_tests: false,
             lens: LensConfig::default(),
         }
     }
@@ -229,6 +231,7 @@ impl Config {
         set(value, "/completion/addCallParenthesis", &mut self.completion.add_call_parenthesis);
         set(value, "/completion/addCallArgumentSnippets", &mut self.completion.add_call_argument_snippets);
         set(value, "/callInfo/full", &mut self.call_info_full);
+        set(value, "/references/excludeTests", &mut self.references_exclude_tests);
 
         let mut lens_enabled = true;
         set(value, "/lens/enable", &mut lens_enabled);
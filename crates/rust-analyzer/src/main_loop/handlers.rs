@@ -19,8 +19,8 @@ use lsp_types::{
 };
 use ra_cfg::CfgExpr;
 use ra_ide::{
-    FileId, FilePosition, FileRange, Query, RangeInfo, Runnable, RunnableKind, SearchScope,
-    TextEdit,
+    FileId, FilePosition, FileRange, FindAllRefsConfig, Query, RangeInfo, Runnable, RunnableKind,
+    SearchScope, TextEdit,
 };
 use ra_prof::profile;
 use ra_project_model::TargetKind;
@@ -558,10 +558,9 @@ pub fn handle_prepare_rename(
     let _p = profile("handle_prepare_rename");
     let position = from_proto::file_position(&world, params)?;
 
-    let optional_change = world.analysis().rename(position, "dummy")?;
-    let range = match optional_change {
+    let range = match world.analysis().prepare_rename(position)? {
         None => return Ok(None),
-        Some(it) => it.range,
+        Some(it) => it,
     };
 
     let line_index = world.analysis().file_line_index(position.file_id)?;
@@ -583,8 +582,11 @@ pub fn handle_rename(world: WorldSnapshot, params: RenameParams) -> Result<Optio
 
     let optional_change = world.analysis().rename(position, &*params.new_name)?;
     let source_change = match optional_change {
-        None => return Ok(None),
-        Some(it) => it.info,
+        Ok(None) => return Ok(None),
+        Ok(Some(it)) => it.info,
+        Err(err) => {
+            return Err(LspError::new(ErrorCode::InvalidParams as i32, err.info.to_string()).into())
+        }
     };
     let workspace_edit = to_proto::workspace_edit(&world, source_change)?;
     Ok(Some(workspace_edit))
@@ -597,22 +599,20 @@ pub fn handle_references(
     let _p = profile("handle_references");
     let position = from_proto::file_position(&world, params.text_document_position)?;
 
-    let refs = match world.analysis().find_all_refs(position, None)? {
+    let config = FindAllRefsConfig {
+        exclude_tests: world.config.references_exclude_tests,
+        ..FindAllRefsConfig::default()
+    };
+    let refs = match world.analysis().find_all_refs(position, &config)? {
         None => return Ok(None),
         Some(refs) => refs,
     };
 
-    let locations = if params.context.include_declaration {
-        refs.into_iter()
-            .filter_map(|reference| to_proto::location(&world, reference.file_range).ok())
-            .collect()
-    } else {
-        // Only iterate over the references if include_declaration was false
-        refs.references()
-            .iter()
-            .filter_map(|reference| to_proto::location(&world, reference.file_range).ok())
-            .collect()
-    };
+    let refs = if params.context.include_declaration { refs } else { refs.references_only() };
+    let locations = refs
+        .into_iter()
+        .filter_map(|reference| to_proto::location(&world, reference.file_range).ok())
+        .collect();
 
     Ok(Some(locations))
 }
@@ -910,10 +910,11 @@ pub fn handle_document_highlight(
     let position = from_proto::file_position(&world, params.text_document_position_params)?;
     let line_index = world.analysis().file_line_index(position.file_id)?;
 
-    let refs = match world
-        .analysis()
-        .find_all_refs(position, Some(SearchScope::single_file(position.file_id)))?
-    {
+    let config = FindAllRefsConfig {
+        search_scope: Some(SearchScope::single_file(position.file_id)),
+        ..FindAllRefsConfig::default()
+    };
+    let refs = match world.analysis().find_all_refs(position, &config)? {
         None => return Ok(None),
         Some(refs) => refs,
     };
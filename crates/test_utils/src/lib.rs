@@ -10,20 +10,29 @@
 pub mod mark;
 
 use std::{
-    fs,
+    fmt, fs,
+    hash::{Hash, Hasher},
     path::{Path, PathBuf},
 };
 
 pub use ra_cfg::CfgOptions;
 
+use difference::Difference;
+use regex::Regex;
 pub use relative_path::{RelativePath, RelativePathBuf};
 pub use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashSet, FxHasher};
 use serde_json::Value;
 use text_size::{TextRange, TextSize};
 
 pub use difference::Changeset as __Changeset;
 
 pub const CURSOR_MARKER: &str = "<|>";
+/// Alternative cursor marker, which reads better in fixtures full of generics
+/// and closures. It follows the snippet syntax of LSP clients.
+pub const CURSOR_MARKER_DOLLAR: &str = "$0";
+/// Escaped form of `CURSOR_MARKER`, decoded into a literal `<|>` in extracted text.
+pub const ESCAPED_CURSOR_MARKER: &str = "<\\|>";
 
 /// Asserts that two strings are equal, otherwise displays a rich diff between them.
 ///
@@ -36,37 +45,378 @@ macro_rules! assert_eq_text {
     ($left:expr, $right:expr) => {
         assert_eq_text!($left, $right,)
     };
+    ($left:expr, $right:expr, $($tt:tt)*) => {
+        $crate::assert_eq_text_with!($left, $right, std::convert::identity, $($tt)*)
+    };
+}
+
+/// Shared body of the `assert_eq_text*` macros: unless `equal`, prints both
+/// strings and their diff, with `split` separating the compared chunks.
+/// Returns `equal`.
+#[doc(hidden)]
+pub fn __check_eq_text(left: &str, right: &str, split: &str, equal: bool) -> bool {
+    if equal {
+        return true;
+    }
+    if left.trim() == right.trim() {
+        eprintln!(
+            "Left:\n{}\n\nRight:\n{}\n\nWhitespace difference\n",
+            __paint_removed(&__show_whitespace(left)),
+            __paint_added(&__show_whitespace(right)),
+        );
+    } else {
+        let changeset = __Changeset::new(right, left, split);
+        let diff = __format_changeset(&changeset);
+        eprintln!("Left:\n{}\n\nRight:\n{}\n\nDiff:\n{}\n", left, right, diff);
+    }
+    false
+}
+
+/// Whether diffs should be colored: stderr must be a terminal, and neither
+/// `NO_COLOR` nor `TERM=dumb` may be set.
+fn use_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none()
+        && std::env::var("TERM").map_or(false, |term| term != "dumb")
+        && atty::is(atty::Stream::Stderr)
+}
+
+fn paint(text: &str, color: &str, plain: (&str, &str), use_color: bool) -> String {
+    if use_color {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        format!("{}{}{}", plain.0, text, plain.1)
+    }
+}
+
+#[doc(hidden)]
+pub fn __paint_added(text: &str) -> String {
+    paint(text, "32", ("", ""), use_color())
+}
+
+#[doc(hidden)]
+pub fn __paint_removed(text: &str) -> String {
+    paint(text, "31", ("", ""), use_color())
+}
+
+/// Makes tabs, trailing spaces and line endings visible as `→`, `·` and
+/// `↵` (`␍↵` for `\r\n`).
+#[doc(hidden)]
+pub fn __show_whitespace(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            let (line, cr) =
+                if line.ends_with('\r') { (&line[..line.len() - 1], "␍") } else { (line, "") };
+            let content = line.trim_end_matches(' ');
+            let trailing = "·".repeat(line.len() - content.len());
+            format!("{}{}{}", content.replace('\t', "→"), trailing, cr)
+        })
+        .collect::<Vec<_>>()
+        .join("↵\n")
+}
+
+#[test]
+fn show_whitespace_marks_tabs_trailing_spaces_and_newlines() {
+    assert_eq!(__show_whitespace("a \tb  \n\tc\n"), "a →b··↵\n→c↵\n");
+    assert_eq!(__show_whitespace("a b \r\n"), "a b·␍↵\n");
+}
+
+/// Formats the diff with additions in green and deletions in red, or with
+/// `{+added+}` and `[-removed-]` markers if colors are disabled.
+#[doc(hidden)]
+pub fn __format_changeset(changeset: &__Changeset) -> String {
+    format_changeset_impl(changeset, use_color())
+}
+
+fn format_changeset_impl(changeset: &__Changeset, use_color: bool) -> String {
+    changeset
+        .diffs
+        .iter()
+        .map(|diff| match diff {
+            Difference::Same(it) => it.clone(),
+            Difference::Add(it) => paint(it, "32", ("{+", "+}"), use_color),
+            Difference::Rem(it) => paint(it, "31", ("[-", "-]"), use_color),
+        })
+        .collect::<Vec<_>>()
+        .join(&changeset.split)
+}
+
+#[test]
+fn format_changeset_without_colors() {
+    let changeset = __Changeset::new("fn foo() {}", "fn bar() {}", " ");
+    assert_eq!(format_changeset_impl(&changeset, false), "fn [-foo()-] {+bar()+} {}");
+    assert_eq!(
+        format_changeset_impl(&changeset, true),
+        "fn \x1b[31mfoo()\x1b[0m \x1b[32mbar()\x1b[0m {}"
+    );
+}
+
+/// Same as `assert_eq_text!`, but the diff is computed character by character
+/// rather than line by line, so that only the differing words are highlighted.
+#[macro_export]
+macro_rules! assert_eq_text_words {
+    ($left:expr, $right:expr) => {
+        assert_eq_text_words!($left, $right,)
+    };
     ($left:expr, $right:expr, $($tt:tt)*) => {{
         let left = $left;
         let right = $right;
-        if left != right {
-            if left.trim() == right.trim() {
-                eprintln!("Left:\n{:?}\n\nRight:\n{:?}\n\nWhitespace difference\n", left, right);
-            } else {
-                let changeset = $crate::__Changeset::new(right, left, "\n");
-                eprintln!("Left:\n{}\n\nRight:\n{}\n\nDiff:\n{}\n", left, right, changeset);
-            }
+        if !$crate::__check_eq_text(&left[..], &right[..], "", left[..] == right[..]) {
             eprintln!($($tt)*);
             panic!("text differs");
         }
     }};
 }
 
+#[test]
+#[should_panic(expected = "text differs")]
+fn assert_eq_text_words_detects_differences() {
+    assert_eq_text_words!("fn foo() {}", "fn foo() {}");
+    assert_eq_text_words!("fn foo() {}", "fn bar() {}");
+}
+
+#[test]
+fn word_changeset_marks_only_changed_words() {
+    let changeset = __Changeset::new("fn foo() {}", "fn bar() {}", "");
+    assert_eq!(format_changeset_impl(&changeset, false), "fn [-foo-]{+bar+}() {}");
+}
+
+/// Same as `assert_eq_text!`, but the strings are equal if they are equal
+/// after applying `normalize: Fn(&str) -> String` to both. The diff still
+/// shows the original strings.
+#[macro_export]
+macro_rules! assert_eq_text_with {
+    ($left:expr, $right:expr, $normalize:expr) => {
+        assert_eq_text_with!($left, $right, $normalize,)
+    };
+    ($left:expr, $right:expr, $normalize:expr, $($tt:tt)*) => {{
+        let left = $left;
+        let right = $right;
+        let normalize = $normalize;
+        let equal = normalize(&left[..]) == normalize(&right[..]);
+        if !$crate::__check_eq_text(&left[..], &right[..], "\n", equal) {
+            eprintln!($($tt)*);
+            panic!("text differs");
+        }
+    }};
+}
+
+#[test]
+fn assert_eq_text_with_normalizes_both_sides() {
+    let re = Regex::new("0x[0-9a-f]+").unwrap();
+    let normalize = |text: &str| re.replace_all(text, "0x..").into_owned();
+    assert_eq_text_with!("ptr: 0x7ffd5e8c\n", "ptr: 0xdeadbeef\n", normalize);
+    assert_eq_text_with!("ptr: 0x1".to_string(), "ptr: 0x2", normalize);
+}
+
+#[test]
+#[should_panic(expected = "text differs")]
+fn assert_eq_text_with_detects_differences() {
+    let re = Regex::new("0x[0-9a-f]+").unwrap();
+    let normalize = |text: &str| re.replace_all(text, "0x..").into_owned();
+    assert_eq_text_with!("ptr: 0x1", "addr: 0x1", normalize);
+}
+
 /// Infallible version of `try_extract_offset()`.
 pub fn extract_offset(text: &str) -> (TextSize, String) {
     match try_extract_offset(text) {
-        None => panic!("text should contain cursor marker"),
-        Some(result) => result,
+        None => panic!("text should contain cursor marker, {}", describe_text(text)),
+        Some((offset, text)) => {
+            debug_assert_no_markers(&text);
+            let mut offsets = [offset];
+            let text = unescape_markers(&text, &mut offsets);
+            (offsets[0], text)
+        }
     }
 }
 
-/// Returns the offset of the first occurence of `<|>` marker and the copy of `text`
-/// without the marker.
+/// Describes `text` by its first and last 40 characters for panic messages.
+fn describe_text(text: &str) -> String {
+    const EDGE: usize = 40;
+    let len = text.chars().count();
+    if len <= 2 * EDGE {
+        return format!("got {} bytes: {:?}", text.len(), text);
+    }
+    let head: String = text.chars().take(EDGE).collect();
+    let mut tail: Vec<char> = text.chars().rev().take(EDGE).collect();
+    tail.reverse();
+    let tail: String = tail.into_iter().collect();
+    format!("got {} bytes: {:?} ... {:?}", text.len(), head, tail)
+}
+
+#[test]
+#[should_panic(expected = "text should contain cursor marker, got 11 bytes: \"fn foo() {}\"")]
+fn extract_offset_describes_text_without_marker() {
+    extract_offset("fn foo() {}");
+}
+
+/// Returns the offset of the first occurence of `<|>` or `$0` marker and the
+/// copy of `text` without the marker.
+///
+/// # Panics
+/// Panics if `text` mixes both kinds of markers.
 fn try_extract_offset(text: &str) -> Option<(TextSize, String)> {
-    let cursor_pos = text.find(CURSOR_MARKER)?;
-    let mut new_text = String::with_capacity(text.len() - CURSOR_MARKER.len());
+    if text.contains(CURSOR_MARKER) {
+        assert!(
+            !text.contains(CURSOR_MARKER_DOLLAR),
+            "text should not mix {} and {} cursor markers",
+            CURSOR_MARKER,
+            CURSOR_MARKER_DOLLAR
+        );
+        return try_extract_offset_with(CURSOR_MARKER, text);
+    }
+    try_extract_offset_with(CURSOR_MARKER_DOLLAR, text)
+}
+
+/// Returns the offset of the first occurence of `marker` and the copy of `text`
+/// without the marker.
+pub fn try_extract_offset_with(marker: &str, text: &str) -> Option<(TextSize, String)> {
+    try_extract_marker(text, marker)
+}
+
+#[test]
+fn dollar_cursor_marker_works() {
+    let (offset, text) = extract_offset("fn foo<T>(t: T) { let f = |x| x; $0f(t) }");
+    assert_eq!(text, "fn foo<T>(t: T) { let f = |x| x; f(t) }");
+    assert_eq!(offset, TextSize::from(33));
+
+    let (range, text) = extract_range("fn $0foo$0() {}");
+    assert_eq!(text, "fn foo() {}");
+    assert_eq!(range, TextRange::new(3.into(), 6.into()));
+}
+
+#[test]
+#[should_panic(expected = "should not mix")]
+fn mixed_cursor_markers_are_rejected() {
+    extract_range("fn <|>foo$0() {}");
+}
+
+/// Returns the offsets of all `<|>` markers in document order and the copy of
+/// `text` without the markers.
+pub fn extract_all_offsets(text: &str) -> (Vec<TextSize>, String) {
+    let mut offsets = Vec::new();
+    let mut text = text.to_string();
+    while let Some((offset, new_text)) = try_extract_offset(&text) {
+        offsets.push(offset);
+        text = new_text;
+    }
+    let text = unescape_markers(&text, &mut offsets);
+    (offsets, text)
+}
+
+#[test]
+fn extract_all_offsets_works() {
+    let (offsets, text) = extract_all_offsets("<|>fn foo<|>() {<|>}");
+    assert_eq!(text, "fn foo() {}");
+    assert_eq!(offsets, vec![TextSize::from(0), TextSize::from(6), TextSize::from(10)]);
+
+    let (offsets, text) = extract_all_offsets("fn foo() {}");
+    assert_eq!(text, "fn foo() {}");
+    assert!(offsets.is_empty());
+}
+
+/// Same as `extract_offset`, but looks for a named `<|name|>` marker.
+pub fn extract_named_offset(text: &str, name: &str) -> (TextSize, String) {
+    let marker = format!("<|{}|>", name);
+    match try_extract_marker(text, &marker) {
+        None => panic!("text should contain {} marker", marker),
+        Some(result) => result,
+    }
+}
+
+/// Panics if `text` still contains a `<|>` or a named `<|name|>` marker.
+pub fn assert_no_markers(text: &str) {
+    if let Some(offset) = find_marker(text) {
+        panic!("text should not contain markers, found one at offset {}", offset);
+    }
+}
+
+fn debug_assert_no_markers(text: &str) {
+    if cfg!(debug_assertions) {
+        assert_no_markers(text)
+    }
+}
+
+fn find_marker(text: &str) -> Option<usize> {
+    text.match_indices("<|").map(|(idx, _)| idx).find(|&idx| {
+        let rest = &text[idx + "<|".len()..];
+        if rest.starts_with('>') {
+            return true;
+        }
+        match rest.find("|>") {
+            Some(end) => end > 0 && rest[..end].chars().all(|c| c.is_alphanumeric() || c == '_'),
+            None => false,
+        }
+    })
+}
+
+#[test]
+fn assert_no_markers_accepts_clean_text() {
+    assert_no_markers("fn foo(x: Vec<u8>) -> bool { x.iter().any(|it| *it > 0) }");
+    assert_no_markers("<||>");
+}
+
+#[test]
+#[should_panic(expected = "found one at offset 3")]
+fn assert_no_markers_reports_offset() {
+    assert_no_markers("fn <|>foo() {}");
+}
+
+#[test]
+#[should_panic(expected = "found one at offset 0")]
+fn assert_no_markers_detects_named_markers() {
+    assert_no_markers("<|def|>fn foo() {}");
+}
+
+/// Extracts all named `<|name|>` markers from the `text`, returning their
+/// offsets in the copy of `text` without any markers. The anonymous `<|>`
+/// marker is stored under the empty name.
+///
+/// # Panics
+/// Panics if the same marker is present more than once.
+pub fn extract_named_offsets(text: &str) -> (FxHashMap<String, TextSize>, String) {
+    let mut offsets = FxHashMap::default();
+    let mut text = text.to_string();
+    while let Some(start) = find_marker(&text) {
+        let rest = &text[start + "<|".len()..];
+        let (name, len) = if rest.starts_with('>') {
+            (String::new(), CURSOR_MARKER.len())
+        } else {
+            let name_len = rest.find("|>").unwrap();
+            (rest[..name_len].to_string(), "<|".len() + name_len + "|>".len())
+        };
+        text.replace_range(start..start + len, "");
+        let prev = offsets.insert(name.clone(), TextSize::from(start as u32));
+        if prev.is_some() {
+            match name.as_str() {
+                "" => panic!("duplicate marker {}", CURSOR_MARKER),
+                _ => panic!("duplicate marker <|{}|>", name),
+            }
+        }
+    }
+    (offsets, text)
+}
+
+#[test]
+fn extract_named_offsets_works() {
+    let (offsets, text) = extract_named_offsets("fn <|def|>foo() { <|>foo<|use|>() }");
+    assert_eq!(text, "fn foo() { foo() }");
+    assert_eq!(offsets.len(), 3);
+    assert_eq!(offsets["def"], TextSize::from(3));
+    assert_eq!(offsets[""], TextSize::from(11));
+    assert_eq!(offsets["use"], TextSize::from(14));
+}
+
+#[test]
+#[should_panic(expected = "duplicate marker <|foo|>")]
+fn extract_named_offsets_rejects_duplicates() {
+    extract_named_offsets("<|foo|>fn foo() {}<|foo|>");
+}
+
+fn try_extract_marker(text: &str, marker: &str) -> Option<(TextSize, String)> {
+    let cursor_pos = text.find(marker)?;
+    let mut new_text = String::with_capacity(text.len() - marker.len());
     new_text.push_str(&text[..cursor_pos]);
-    new_text.push_str(&text[cursor_pos + CURSOR_MARKER.len()..]);
+    new_text.push_str(&text[cursor_pos + marker.len()..]);
     let cursor_pos = TextSize::from(cursor_pos as u32);
     Some((cursor_pos, new_text))
 }
@@ -74,8 +424,11 @@ fn try_extract_offset(text: &str) -> Option<(TextSize, String)> {
 /// Infallible version of `try_extract_range()`.
 pub fn extract_range(text: &str) -> (TextRange, String) {
     match try_extract_range(text) {
-        None => panic!("text should contain cursor marker"),
-        Some(result) => result,
+        None => panic!("text should contain cursor marker, {}", describe_text(text)),
+        Some((range, text)) => {
+            debug_assert_no_markers(&text);
+            unescape_range(range, &text)
+        }
     }
 }
 
@@ -87,6 +440,49 @@ fn try_extract_range(text: &str) -> Option<(TextRange, String)> {
     Some((TextRange::new(start, end), text))
 }
 
+fn unescape_range(range: TextRange, text: &str) -> (TextRange, String) {
+    let mut offsets = [range.start(), range.end()];
+    let text = unescape_markers(text, &mut offsets);
+    (TextRange::new(offsets[0], offsets[1]), text)
+}
+
+/// Decodes `<\|>` escapes into literal `<|>` markers, shifting `offsets`
+/// (which point into the escaped `text`) accordingly.
+fn unescape_markers(text: &str, offsets: &mut [TextSize]) -> String {
+    let shrink = ESCAPED_CURSOR_MARKER.len() - CURSOR_MARKER.len();
+    for offset in offsets.iter_mut() {
+        let escapes = text[..usize::from(*offset)].matches(ESCAPED_CURSOR_MARKER).count();
+        *offset -= TextSize::from((escapes * shrink) as u32);
+    }
+    text.replace(ESCAPED_CURSOR_MARKER, CURSOR_MARKER)
+}
+
+#[test]
+fn escaped_cursor_marker_is_kept_literally() {
+    let (offset, text) = extract_offset(r#"let s = "<\|>"; <|>s"#);
+    assert_eq!(text, r#"let s = "<|>"; s"#);
+    assert_eq!(offset, TextSize::from(15));
+
+    let (range, text) = extract_range(r#"<|>let s<|> = "<\|>";"#);
+    assert_eq!(text, r#"let s = "<|>";"#);
+    assert_eq!(range, TextRange::new(0.into(), 5.into()));
+
+    match extract_range_or_offset(r#"<\|> <|>x <\|>"#) {
+        (RangeOrOffset::Offset(offset), text) => {
+            assert_eq!(text, "<|> x <|>");
+            assert_eq!(offset, TextSize::from(4));
+        }
+        (RangeOrOffset::Range(_), _) => panic!("expected an offset"),
+    }
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "found one at offset 6")]
+fn extract_range_or_offset_rejects_extra_markers() {
+    extract_range_or_offset("<|>fn f<|>oo<|>() {}");
+}
+
 #[derive(Clone, Copy)]
 pub enum RangeOrOffset {
     Range(TextRange),
@@ -109,46 +505,282 @@ impl From<RangeOrOffset> for TextRange {
 /// Panics if no `<|>` marker is present in the `text`.
 pub fn extract_range_or_offset(text: &str) -> (RangeOrOffset, String) {
     if let Some((range, text)) = try_extract_range(text) {
+        debug_assert_no_markers(&text);
+        let (range, text) = unescape_range(range, &text);
         return (RangeOrOffset::Range(range), text);
     }
     let (offset, text) = extract_offset(text);
     (RangeOrOffset::Offset(offset), text)
 }
 
-/// Extracts ranges, marked with `<tag> </tag>` pairs from the `text`
-pub fn extract_ranges(mut text: &str, tag: &str) -> (Vec<TextRange>, String) {
-    let open = format!("<{}>", tag);
-    let close = format!("</{}>", tag);
+/// Describes how the opening and closing tags look like for `extract_ranges`.
+/// `{}` in the delimiters is substituted with the tag name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagSyntax {
+    pub open: &'static str,
+    pub close: &'static str,
+}
+
+/// Default `<tag> </tag>` syntax.
+pub const DEFAULT_TAG_SYNTAX: TagSyntax = TagSyntax { open: "<{}>", close: "</{}>" };
+
+/// Extracts ranges, marked with `<tag> </tag>` pairs from the `text`.
+/// Any other `<...>`, like generic arguments, is kept verbatim.
+pub fn extract_ranges(text: &str, tag: &str) -> (Vec<TextRange>, String) {
+    extract_ranges_with_syntax(text, tag, &DEFAULT_TAG_SYNTAX)
+}
+
+/// Same as `extract_ranges`, but tags are written using the given `syntax`.
+pub fn extract_ranges_with_syntax(
+    text: &str,
+    tag: &str,
+    syntax: &TagSyntax,
+) -> (Vec<TextRange>, String) {
+    let (ranges, text) = extract_nested_ranges(text, tag, syntax);
+    let mut ranges: Vec<TextRange> = ranges.into_iter().map(|(range, _depth)| range).collect();
+    ranges.sort_by_key(|r| (r.start(), r.end()));
+    (ranges, text)
+}
+
+/// Same as `extract_ranges`, but enclosing ranges precede the ranges nested
+/// inside them. Ranges at the same depth are sorted by start offset.
+pub fn extract_ranges_by_depth(text: &str, tag: &str) -> (Vec<TextRange>, String) {
+    let (mut ranges, text) = extract_nested_ranges(text, tag, &DEFAULT_TAG_SYNTAX);
+    ranges.sort_by_key(|&(r, depth)| (depth, r.start(), r.end()));
+    (ranges.into_iter().map(|(range, _depth)| range).collect(), text)
+}
+
+/// A range extracted by `extract_ranges_tree`, with the ranges nested inside it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeNode {
+    pub range: TextRange,
+    pub children: Vec<RangeNode>,
+}
+
+/// Same as `extract_ranges`, but preserves the nesting of the tags: every
+/// range holds the ranges enclosed by it. Siblings are sorted by start offset.
+pub fn extract_ranges_tree(text: &str, tag: &str) -> (Vec<RangeNode>, String) {
+    let (ranges, text) = extract_nested_ranges(text, tag, &DEFAULT_TAG_SYNTAX);
+    // Ranges come in the order of their closing tags, so children always
+    // precede their parent.
+    let mut pending: Vec<Vec<RangeNode>> = Vec::new();
+    for (range, depth) in ranges {
+        if pending.len() < depth + 2 {
+            pending.resize_with(depth + 2, Vec::new);
+        }
+        let children = std::mem::take(&mut pending[depth + 1]);
+        pending[depth].push(RangeNode { range, children });
+    }
+    let roots = pending.into_iter().next().unwrap_or_default();
+    (roots, text)
+}
+
+#[test]
+fn extract_ranges_tree_preserves_nesting() {
+    let (roots, text) = extract_ranges_tree("<r>a<r>b</r><r>c</r></r>d<r>e</r>", "r");
+    assert_eq!(text, "abcde");
+    let leaf = |start: u32, end: u32| RangeNode {
+        range: TextRange::new(start.into(), end.into()),
+        children: Vec::new(),
+    };
+    assert_eq!(
+        roots,
+        vec![
+            RangeNode {
+                range: TextRange::new(0.into(), 3.into()),
+                children: vec![leaf(1, 2), leaf(2, 3)],
+            },
+            leaf(4, 5),
+        ]
+    );
+}
+
+/// Returns unsorted ranges together with their nesting depth.
+fn extract_nested_ranges(
+    mut text: &str,
+    tag: &str,
+    syntax: &TagSyntax,
+) -> (Vec<(TextRange, usize)>, String) {
+    let open = syntax.open.replace("{}", tag);
+    let close = syntax.close.replace("{}", tag);
     let mut ranges = Vec::new();
     let mut res = String::new();
     let mut stack = Vec::new();
     loop {
-        match text.find('<') {
-            None => {
+        let (i, is_open) = match (text.find(&open), text.find(&close)) {
+            (None, None) => {
                 res.push_str(text);
                 break;
             }
-            Some(i) => {
-                res.push_str(&text[..i]);
-                text = &text[i..];
-                if text.starts_with(&open) {
-                    text = &text[open.len()..];
-                    let from = TextSize::of(&res);
-                    stack.push(from);
-                } else if text.starts_with(&close) {
-                    text = &text[close.len()..];
-                    let from = stack.pop().unwrap_or_else(|| panic!("unmatched </{}>", tag));
-                    let to = TextSize::of(&res);
-                    ranges.push(TextRange::new(from, to));
-                }
+            (Some(o), Some(c)) if c < o => (c, false),
+            (Some(o), _) => (o, true),
+            (None, Some(c)) => (c, false),
+        };
+        res.push_str(&text[..i]);
+        text = &text[i..];
+        if is_open {
+            text = &text[open.len()..];
+            let from = TextSize::of(&res);
+            stack.push(from);
+        } else {
+            text = &text[close.len()..];
+            let from = stack.pop().unwrap_or_else(|| panic!("unmatched {}", close));
+            let to = TextSize::of(&res);
+            ranges.push((TextRange::new(from, to), stack.len()));
+        }
+    }
+    assert!(stack.is_empty(), "unmatched {}", open);
+    (ranges, res)
+}
+
+#[test]
+fn extract_ranges_keeps_other_angle_brackets() {
+    let (ranges, text) =
+        extract_ranges("fn foo<T>(<tag>x: Vec<T></tag>) -> <tag>Option<T></tag> {}", "tag");
+    assert_eq!(text, "fn foo<T>(x: Vec<T>) -> Option<T> {}");
+    assert_eq!(
+        ranges,
+        vec![TextRange::new(10.into(), 19.into()), TextRange::new(24.into(), 33.into())]
+    );
+}
+
+#[test]
+fn extract_ranges_by_depth_puts_outer_ranges_first() {
+    let (ranges, text) = extract_ranges_by_depth("<r>a<r>b</r></r><r>c<r>d<r>e</r></r></r>", "r");
+    assert_eq!(text, "abcde");
+    assert_eq!(
+        ranges,
+        vec![
+            TextRange::new(0.into(), 2.into()),
+            TextRange::new(2.into(), 5.into()),
+            TextRange::new(1.into(), 2.into()),
+            TextRange::new(3.into(), 5.into()),
+            TextRange::new(4.into(), 5.into()),
+        ]
+    );
+}
+
+#[test]
+fn extract_ranges_with_custom_syntax() {
+    let syntax = TagSyntax { open: "$[{}]", close: "$[/{}]" };
+    let (ranges, text) =
+        extract_ranges_with_syntax("fn foo<T>($[sel]x: T$[/sel]) -> Vec<T> {}", "sel", &syntax);
+    assert_eq!(text, "fn foo<T>(x: T) -> Vec<T> {}");
+    assert_eq!(ranges, vec![TextRange::new(10.into(), 14.into())]);
+}
+
+/// Extracts ranges for each of the `tags` in a single pass, so offsets are
+/// consistent across tags. Ranges of different tags may overlap. Tags which
+/// are not listed are left in the text as is.
+pub fn extract_multi_tagged_ranges(
+    mut text: &str,
+    tags: &[&str],
+) -> (FxHashMap<String, Vec<TextRange>>, String) {
+    let mut ranges: FxHashMap<String, Vec<TextRange>> =
+        tags.iter().map(|&tag| (tag.to_string(), Vec::new())).collect();
+    let mut stacks: FxHashMap<&str, Vec<TextSize>> = FxHashMap::default();
+    let mut res = String::new();
+    while let Some(i) = text.find('<') {
+        res.push_str(&text[..i]);
+        text = &text[i + 1..];
+        let is_open = !text.starts_with('/');
+        let body = if is_open { text } else { &text[1..] };
+        let tag = tags
+            .iter()
+            .copied()
+            .find(|tag| body.starts_with(tag) && body[tag.len()..].starts_with('>'));
+        let tag = match tag {
+            Some(it) => it,
+            None => {
+                res.push('<');
+                continue;
             }
+        };
+        text = &body[tag.len() + 1..];
+        let offset = TextSize::of(&res);
+        let stack = stacks.entry(tag).or_default();
+        if is_open {
+            stack.push(offset);
+        } else {
+            let from = stack.pop().unwrap_or_else(|| panic!("unmatched </{}>", tag));
+            ranges.get_mut(tag).unwrap().push(TextRange::new(from, offset));
         }
     }
-    assert!(stack.is_empty(), "unmatched <{}>", tag);
-    ranges.sort_by_key(|r| (r.start(), r.end()));
+    res.push_str(text);
+    for (tag, stack) in stacks {
+        assert!(stack.is_empty(), "unmatched <{}>", tag);
+    }
+    for ranges in ranges.values_mut() {
+        ranges.sort_by_key(|r| (r.start(), r.end()));
+    }
     (ranges, res)
 }
 
+#[test]
+fn extract_multi_tagged_ranges_works() {
+    let (ranges, text) = extract_multi_tagged_ranges(
+        "<a>fn <b>foo</a><T>()</b> -> <c>Vec<T></c> {}",
+        &["a", "b", "c"],
+    );
+    assert_eq!(text, "fn foo<T>() -> Vec<T> {}");
+    assert_eq!(ranges["a"], vec![TextRange::new(0.into(), 6.into())]);
+    assert_eq!(ranges["b"], vec![TextRange::new(3.into(), 11.into())]);
+    assert_eq!(ranges["c"], vec![TextRange::new(15.into(), 21.into())]);
+}
+
+/// Extracts the `<|>` marker and ranges marked with `<tag> </tag>` pairs,
+/// with all offsets relative to the text without any markup.
+pub fn extract_offset_and_ranges(text: &str, tag: &str) -> (TextSize, Vec<TextRange>, String) {
+    let (ranges, with_cursor) = extract_ranges(text, tag);
+    let (offset, text) = extract_offset(&with_cursor);
+    let marker_len = TextSize::of(&with_cursor) - TextSize::of(&text);
+    let shift = |pos: TextSize| if pos >= offset + marker_len { pos - marker_len } else { pos };
+    let ranges =
+        ranges.into_iter().map(|r| TextRange::new(shift(r.start()), shift(r.end()))).collect();
+    (offset, ranges, text)
+}
+
+#[test]
+fn extract_offset_and_ranges_works() {
+    let (offset, ranges, text) =
+        extract_offset_and_ranges("fn <h>foo</h>() { <h><|>foo</h>(); <h>foo</h>() }", "h");
+    assert_eq!(text, "fn foo() { foo(); foo() }");
+    assert_eq!(offset, TextSize::from(11));
+    assert_eq!(
+        ranges,
+        vec![
+            TextRange::new(3.into(), 6.into()),
+            TextRange::new(11.into(), 14.into()),
+            TextRange::new(18.into(), 21.into()),
+        ]
+    );
+}
+
+/// Same as `extract_ranges`, but touching or overlapping ranges are merged
+/// into a single `TextRange`.
+pub fn extract_ranges_merged(text: &str, tag: &str) -> (Vec<TextRange>, String) {
+    let (ranges, text) = extract_ranges(text, tag);
+    let mut merged: Vec<TextRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start() <= last.end() => *last = last.cover(range),
+            _ => merged.push(range),
+        }
+    }
+    (merged, text)
+}
+
+#[test]
+fn extract_ranges_merged_joins_adjacent_ranges() {
+    let (ranges, text) =
+        extract_ranges_merged("<sel>foo</sel><sel>bar</sel> <sel>baz</sel>", "sel");
+    assert_eq!(text, "foobar baz");
+    assert_eq!(
+        ranges,
+        vec![TextRange::new(0.into(), 6.into()), TextRange::new(7.into(), 10.into())]
+    );
+}
+
 /// Inserts `<|>` marker into the `text` at `offset`.
 pub fn add_cursor(text: &str, offset: TextSize) -> String {
     let offset: usize = offset.into();
@@ -159,15 +791,234 @@ pub fn add_cursor(text: &str, offset: TextSize) -> String {
     res
 }
 
+/// Inserts a pair of `<|>` markers into the `text` around `range`, the inverse
+/// of `extract_range`.
+pub fn add_range(text: &str, range: TextRange) -> String {
+    let text = add_cursor(text, range.end());
+    add_cursor(&text, range.start())
+}
+
+#[test]
+fn add_range_roundtrips_with_extract_range() {
+    let text = "fn foo() {}";
+    for &(start, end) in &[(0, 0), (3, 6), (6, 6), (0, 11)] {
+        let range = TextRange::new(TextSize::from(start), TextSize::from(end));
+        let (extracted, clean) = extract_range(&add_range(text, range));
+        assert_eq!(extracted, range);
+        assert_eq!(clean, text);
+    }
+    assert_eq!(add_range(text, TextRange::new(3.into(), 3.into())), "fn <|><|>foo() {}");
+}
+
+/// Extracts the `<|>` offset from `before`, runs `f` on the offset and the
+/// clean text, and asserts that the result is equal to `after`. Use
+/// `add_cursor` in `f` to show a resulting position in `after`.
+pub fn check_offset(before: &str, f: impl Fn(TextSize, &str) -> String, after: &str) {
+    let (offset, text) = extract_offset(before);
+    let actual = f(offset, &text);
+    assert_eq_text!(after, &*actual);
+}
+
+#[test]
+fn check_offset_compares_output() {
+    check_offset(
+        "fn <|>foo() {}",
+        |offset, text| add_cursor(text, offset + TextSize::of("foo")),
+        "fn foo<|>() {}",
+    );
+    check_offset("let x<|> = 92;", |_, text| text.replace("92", "62"), "let x = 62;");
+}
+
+/// Replaces the range (or offset) marked with `<|>` in `before` with
+/// `replace_with` and asserts that the result is equal to `after`.
+pub fn assert_edit(before: &str, replace_with: &str, after: &str) {
+    let (range, mut text) = extract_range_or_offset(before);
+    let range: TextRange = range.into();
+    text.replace_range(usize::from(range.start())..usize::from(range.end()), replace_with);
+    assert_eq_text!(after, &*text);
+}
+
+#[test]
+fn assert_edit_applies_replacement() {
+    assert_edit("fn <|>() {}", "foo", "fn foo() {}");
+    assert_edit("fn <|>foo<|>() {}", "bar", "fn bar() {}");
+    assert_edit("fn foo(<|>x: i32<|>) {}", "", "fn foo() {}");
+}
+
+#[test]
+#[should_panic(expected = "text differs")]
+fn assert_edit_detects_differences() {
+    assert_edit("fn <|>foo<|>() {}", "bar", "fn baz() {}");
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct FixtureEntry {
     pub meta: FixtureMeta,
     pub text: String,
 }
 
+impl FixtureEntry {
+    /// Shortcut for `meta.path()`.
+    ///
+    /// ```
+    /// let fixture = test_utils::parse_fixture("//- /lib.rs crate:foo\nmod m;");
+    /// let entry = &fixture[0];
+    /// assert_eq!(entry.path(), "/lib.rs");
+    /// assert_eq!(entry.crate_name(), Some("foo"));
+    /// assert_eq!(entry.text, "mod m;");
+    /// ```
+    pub fn path(&self) -> &RelativePath {
+        self.meta.path()
+    }
+
+    /// Shortcut for `meta.crate_name()`.
+    pub fn crate_name(&self) -> Option<&str> {
+        self.meta.crate_name().map(|it| it.as_str())
+    }
+
+    /// Hash of the entry's text with normalized newlines. Meta is not included.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = FxHasher::default();
+        self.text.replace("\r\n", "\n").hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Whether the entry's body consists only of whitespace, i.e. the fixture
+    /// intentionally describes an empty file.
+    pub fn is_empty_file(&self) -> bool {
+        self.text.trim_start_matches(BOM).trim().is_empty()
+    }
+}
+
+#[test]
+fn fixture_entry_detects_empty_files() {
+    let parsed = parse_fixture(
+        r"
+        //- /lib.rs
+        mod foo;
+        //- /foo.rs
+
+        //- /bar.rs bom
+        ",
+    );
+    assert!(!parsed[0].is_empty_file());
+    assert!(parsed[1].is_empty_file());
+    assert!(parsed[2].is_empty_file());
+}
+
+#[test]
+fn fixture_content_hash_tracks_text() {
+    let fixture = r"
+        //- /lib.rs
+        mod foo;
+        //- /foo.rs crate:foo
+        mod foo;
+        //- /bar.rs
+        struct Bar;
+        ";
+    let first = parse_fixture(fixture);
+    let second = parse_fixture(fixture);
+    assert_eq!(first[0].content_hash(), second[0].content_hash());
+    assert_eq!(first[0].content_hash(), first[1].content_hash());
+    assert_ne!(first[0].content_hash(), first[2].content_hash());
+}
+
+/// A crate declared with `crate:` in a fixture.
+#[derive(Debug, Eq, PartialEq)]
+pub struct CrateMeta {
+    pub name: String,
+    /// Index of the crate root among the fixture entries.
+    pub entry: usize,
+    /// Indices of the dependencies among the crates.
+    pub deps: Vec<usize>,
+}
+
+/// Parses the fixture and resolves the `deps` of every crate, independently
+/// of the order in which crates are declared.
+pub fn parse_crate_graph(
+    ra_fixture: &str,
+) -> Result<(Vec<FixtureEntry>, Vec<CrateMeta>), FixtureError> {
+    let (entries, meta_lines) = parse_fixture_with_meta_lines(ra_fixture)?;
+    let crates: Vec<(usize, &FileMeta)> = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, entry)| match &entry.meta {
+            FixtureMeta::File(f) if f.crate_name.is_some() => Some((idx, f)),
+            _ => None,
+        })
+        .collect();
+
+    let mut res = Vec::new();
+    for &(entry, meta) in crates.iter() {
+        let mut deps = Vec::new();
+        for (alias, actual) in meta.deps.iter() {
+            let dep = actual.as_ref().unwrap_or(alias);
+            match crates.iter().position(|(_, it)| it.crate_name.as_ref() == Some(dep)) {
+                Some(idx) => deps.push(idx),
+                None => {
+                    let (line, ref line_text) = meta_lines[entry];
+                    let column = line_text
+                        .find("deps:")
+                        .and_then(|start| Some(start + line_text[start..].find(dep.as_str())?));
+                    return Err(FixtureError {
+                        kind: FixtureErrorKind::UnknownDependency,
+                        line,
+                        column: column.map(|it| it + 1),
+                        message: format!("unknown dependency {:?}", dep),
+                        line_text: Some(line_text.to_string()),
+                    });
+                }
+            }
+        }
+        res.push(CrateMeta { name: meta.crate_name.clone().unwrap(), entry, deps });
+    }
+    Ok((entries, res))
+}
+
+#[test]
+fn parse_crate_graph_resolves_deps_declared_later() {
+    let (entries, crates) = parse_crate_graph(
+        r"
+        //- /main.rs crate:main deps:foo,bar
+        //- /foo/lib.rs crate:foo deps:bar
+        //- /foo/util.rs
+        //- /bar/lib.rs crate:bar
+        ",
+    )
+    .unwrap();
+    assert_eq!(4, entries.len());
+    assert_eq!(
+        crates,
+        vec![
+            CrateMeta { name: "main".to_string(), entry: 0, deps: vec![1, 2] },
+            CrateMeta { name: "foo".to_string(), entry: 1, deps: vec![2] },
+            CrateMeta { name: "bar".to_string(), entry: 3, deps: vec![] },
+        ]
+    );
+}
+
+#[test]
+fn parse_crate_graph_rejects_unknown_deps() {
+    let err = parse_crate_graph(
+        r"
+        //- /main.rs crate:main deps:foo,baz
+        //- /foo/lib.rs crate:foo
+        ",
+    )
+    .unwrap_err();
+    assert_eq!(err.kind, FixtureErrorKind::UnknownDependency);
+    assert_eq!(err.line, 2);
+    assert_eq!(err.column, Some(34));
+    assert_eq!(err.message, "unknown dependency \"baz\"");
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum FixtureMeta {
-    Root { path: RelativePathBuf },
+    /// A new source root. `cfg` applies to every file in it.
+    Root {
+        path: RelativePathBuf,
+        cfg: CfgOptions,
+    },
     File(FileMeta),
 }
 
@@ -175,16 +1026,28 @@ pub enum FixtureMeta {
 pub struct FileMeta {
     pub path: RelativePathBuf,
     pub crate_name: Option<String>,
-    pub deps: Vec<String>,
+    /// Dependencies as `(alias, actual)`, `actual` is `None` unless the crate
+    /// is renamed with `deps:alias=actual`.
+    pub deps: Vec<(String, Option<String>)>,
+    /// Crates in the extern prelude. Each of them must also be in `deps`.
+    pub extern_prelude: Vec<String>,
     pub cfg: CfgOptions,
     pub edition: Option<String>,
+    /// Target triple, like `x86_64-unknown-linux-gnu`. Not validated.
+    pub target: Option<String>,
     pub env: FxHashMap<String, String>,
+    /// Whether the file text starts with a UTF-8 byte-order mark.
+    pub bom: bool,
+    /// Whether the crate rooted at this file is a proc-macro crate.
+    pub proc_macro: bool,
+    /// Whether the file belongs to a library, like the sysroot's `std`.
+    pub library: bool,
 }
 
 impl FixtureMeta {
     pub fn path(&self) -> &RelativePath {
         match self {
-            FixtureMeta::Root { path } => &path,
+            FixtureMeta::Root { path, .. } => &path,
             FixtureMeta::File(f) => &f.path,
         }
     }
@@ -196,17 +1059,59 @@ impl FixtureMeta {
         }
     }
 
+    pub fn deps(&self) -> &[(String, Option<String>)] {
+        match self {
+            FixtureMeta::File(f) => &f.deps,
+            _ => &[],
+        }
+    }
+
+    pub fn extern_prelude(&self) -> &[String] {
+        match self {
+            FixtureMeta::File(f) => &f.extern_prelude,
+            _ => &[],
+        }
+    }
+
     pub fn cfg_options(&self) -> Option<&CfgOptions> {
         match self {
+            FixtureMeta::Root { cfg, .. } => Some(cfg),
             FixtureMeta::File(f) => Some(&f.cfg),
+        }
+    }
+
+    pub fn edition(&self) -> Option<&String> {
+        match self {
+            FixtureMeta::File(f) => f.edition.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn target(&self) -> Option<&String> {
+        match self {
+            FixtureMeta::File(f) => f.target.as_ref(),
             _ => None,
         }
     }
 
-    pub fn edition(&self) -> Option<&String> {
+    pub fn proc_macro(&self) -> bool {
+        match self {
+            FixtureMeta::File(f) => f.proc_macro,
+            _ => false,
+        }
+    }
+
+    pub fn library(&self) -> bool {
+        match self {
+            FixtureMeta::File(f) => f.library,
+            _ => false,
+        }
+    }
+
+    pub fn bom(&self) -> bool {
         match self {
-            FixtureMeta::File(f) => f.edition.as_ref(),
-            _ => None,
+            FixtureMeta::File(f) => f.bom,
+            _ => false,
         }
     }
 
@@ -246,90 +1151,664 @@ impl FixtureMeta {
 ///  // - other meta
 ///  ```
 pub fn parse_fixture(ra_fixture: &str) -> Vec<FixtureEntry> {
+    try_parse_fixture(ra_fixture).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Fallible version of `parse_fixture`, for validating user-written fixtures.
+pub fn try_parse_fixture(ra_fixture: &str) -> Result<Vec<FixtureEntry>, FixtureError> {
+    parse_fixture_with_meta_lines(ra_fixture).map(|(entries, _)| entries)
+}
+
+/// Also returns the line number and text of the meta line of each entry.
+fn parse_fixture_with_meta_lines(
+    ra_fixture: &str,
+) -> Result<(Vec<FixtureEntry>, Vec<(usize, String)>), FixtureError> {
     let fixture = indent_first_line(ra_fixture);
-    let margin = fixture_margin(&fixture);
+    let margin = match fixture_margin(&fixture) {
+        Some(it) => it,
+        None => {
+            return Err(FixtureError {
+                kind: FixtureErrorKind::EmptyFixture,
+                line: 1,
+                column: None,
+                message: "fixture should contain at least one `//-` meta line".to_string(),
+                line_text: None,
+            })
+        }
+    };
+    // `indent_first_line` inserts an extra line in this case.
+    let line_shift =
+        if ra_fixture.lines().next().map_or(false, |it| it.contains("//-")) { 0 } else { 1 };
+
+    let bad_indentation = |line_no: usize, line: &str, message: &str| FixtureError {
+        kind: FixtureErrorKind::BadIndentation,
+        line: line_no,
+        column: Some(indent_len(line) + 1),
+        message: message.to_string(),
+        line_text: Some(line.to_string()),
+    };
 
-    let mut lines = fixture
-        .split('\n') // don't use `.lines` to not drop `\r\n`
-        .enumerate()
-        .filter_map(|(ix, line)| {
-            if line.len() >= margin {
-                assert!(line[..margin].trim().is_empty());
-                let line_content = &line[margin..];
-                if !line_content.starts_with("//-") {
-                    assert!(
-                        !line_content.contains("//-"),
-                        r#"Metadata line {} has invalid indentation. All metadata lines need to have the same indentation.
-The offending line: {:?}"#,
-                        ix,
-                        line
-                    );
-                }
-                Some(line_content)
-            } else {
-                assert!(line.trim().is_empty());
-                None
+    let mut res: Vec<FixtureEntry> = Vec::new();
+    let mut meta_lines: Vec<(usize, String)> = Vec::new();
+    let mut global_meta = GlobalMeta::default();
+    let push_entry = |res: &mut Vec<FixtureEntry>,
+                      meta_lines: &mut Vec<(usize, String)>,
+                      global_meta: &mut GlobalMeta,
+                      (line_no, line): (usize, String)|
+     -> Result<(), FixtureError> {
+        if is_global_meta(&line) {
+            if !res.is_empty() {
+                return Err(FixtureError {
+                    kind: FixtureErrorKind::MisplacedGlobalMeta,
+                    line: line_no,
+                    column: None,
+                    message: "global meta should come before all entries".to_string(),
+                    line_text: Some(line),
+                });
             }
-        });
+            *global_meta = parse_global_meta(&line, line_no)?;
+            return Ok(());
+        }
+        let mut meta = parse_meta(&line, line_no)?;
+        if let FixtureMeta::File(f) = &mut meta {
+            if f.edition.is_none() {
+                f.edition = global_meta.edition.clone();
+            }
+        }
+        meta_lines.push((line_no, line));
+        let text = if meta.bom() { BOM.to_string() } else { String::new() };
+        res.push(FixtureEntry { meta, text });
+        Ok(())
+    };
 
-    let mut res: Vec<FixtureEntry> = Vec::new();
-    for line in lines.by_ref() {
+    // A `//-` meta line is only parsed once all of its `//+` continuations are seen.
+    let mut pending_meta: Option<(usize, String)> = None;
+    // don't use `.lines` to not drop `\r\n`
+    let line_count = fixture.split('\n').count();
+    for (ix, line) in fixture.split('\n').enumerate() {
+        let line_no = ix + line_shift;
+        if line.len() < margin || !line.is_char_boundary(margin) {
+            if !line.trim().is_empty() {
+                return Err(bad_indentation(
+                    line_no,
+                    line,
+                    "line is indented less than the fixture",
+                ));
+            }
+            continue;
+        }
+        if !line[..margin].trim().is_empty() {
+            return Err(bad_indentation(line_no, line, "line is indented less than the fixture"));
+        }
+        let line = &line[margin..];
+        if line.starts_with("//+") {
+            match &mut pending_meta {
+                Some((_, meta)) => {
+                    meta.push(' ');
+                    meta.push_str(&line["//+".len()..]);
+                }
+                None => {
+                    return Err(FixtureError {
+                        kind: FixtureErrorKind::DanglingContinuation,
+                        line: line_no,
+                        column: Some(margin + 1),
+                        message: "`//+` line should directly follow a `//-` meta line".to_string(),
+                        line_text: Some(line.to_string()),
+                    })
+                }
+            }
+            continue;
+        }
+        if let Some(meta) = pending_meta.take() {
+            push_entry(&mut res, &mut meta_lines, &mut global_meta, meta)?;
+        }
         if line.starts_with("//-") {
-            let meta = line["//-".len()..].trim().to_string();
-            let meta = parse_meta(&meta);
-            res.push(FixtureEntry { meta, text: String::new() })
+            pending_meta = Some((line_no, line.to_string()));
+        } else if line.contains("//-") {
+            return Err(bad_indentation(
+                line_no,
+                line,
+                "all metadata lines need to have the same indentation",
+            ));
         } else if let Some(entry) = res.last_mut() {
             entry.text.push_str(line);
-            entry.text.push('\n');
+            // Only the last line of the fixture can lack a newline.
+            if ix + 1 < line_count {
+                entry.text.push('\n');
+            }
         }
     }
-    res
+    if let Some(meta) = pending_meta.take() {
+        push_entry(&mut res, &mut meta_lines, &mut global_meta, meta)?;
+    }
+
+    for (i, entry) in res.iter().enumerate() {
+        let duplicate = res[..i].iter().position(|prev| {
+            let same_kind = match (&prev.meta, &entry.meta) {
+                (FixtureMeta::Root { .. }, FixtureMeta::Root { .. })
+                | (FixtureMeta::File(_), FixtureMeta::File(_)) => true,
+                _ => false,
+            };
+            same_kind && prev.meta.path() == entry.meta.path()
+        });
+        if let Some(prev) = duplicate {
+            let (line, ref line_text) = meta_lines[i];
+            return Err(FixtureError {
+                kind: FixtureErrorKind::DuplicatePath,
+                line,
+                column: None,
+                message: format!(
+                    "duplicate path {} on lines {} and {}",
+                    entry.meta.path(),
+                    meta_lines[prev].0,
+                    line
+                ),
+                line_text: Some(line_text.to_string()),
+            });
+        }
+    }
+    Ok((res, meta_lines))
+}
+
+#[test]
+fn try_parse_fixture_reports_errors() {
+    let kind = |fixture: &str| try_parse_fixture(fixture).unwrap_err().kind;
+    assert_eq!(kind("fn main() {}"), FixtureErrorKind::EmptyFixture);
+    assert_eq!(kind("//- /lib.rs foo:bar"), FixtureErrorKind::UnknownMetaKey);
+    assert_eq!(
+        kind(
+            r"
+        //- /lib.rs
+          //- /foo.rs
+        "
+        ),
+        FixtureErrorKind::BadIndentation
+    );
+    assert_eq!(
+        kind(
+            r"
+          //- /lib.rs
+        fn foo() {}
+        "
+        ),
+        FixtureErrorKind::BadIndentation
+    );
+
+    let err = try_parse_fixture(
+        r"
+        //- /lib.rs
+        mod foo;
+        //- /lib.rs
+        ",
+    )
+    .unwrap_err();
+    assert_eq!(err.kind, FixtureErrorKind::DuplicatePath);
+    assert_eq!(err.line, 4);
+}
+
+#[test]
+fn parse_fixture_joins_meta_continuations() {
+    let parsed = parse_fixture(
+        r"
+        //- /lib.rs crate:foo
+        //+ deps:bar,baz
+        //+ edition:2015
+        mod m;
+        //- /bar.rs crate:bar
+        ",
+    );
+    assert_eq!(2, parsed.len());
+    assert_eq!("foo", parsed[0].meta.crate_name().unwrap());
+    assert_eq!("2015", parsed[0].meta.edition().unwrap());
+    assert_eq!("mod m;\n", parsed[0].text);
+    assert_eq!(
+        parsed,
+        parse_fixture(
+            "//- /lib.rs crate:foo deps:bar,baz edition:2015\nmod m;\n//- /bar.rs crate:bar\n"
+        )
+    );
+}
+
+#[test]
+#[should_panic(expected = "`//+` line should directly follow a `//-` meta line")]
+fn parse_fixture_rejects_dangling_continuation() {
+    parse_fixture(
+        r"
+        //- /lib.rs
+        mod m;
+        //+ deps:bar
+        ",
+    );
+}
+
+#[test]
+#[should_panic(expected = "duplicate path /foo.rs on lines 3 and 6")]
+fn parse_fixture_rejects_duplicate_paths() {
+    parse_fixture(
+        r"
+        //- /lib.rs
+        //- /foo.rs
+        struct Foo;
+
+        //- /foo.rs
+        struct Bar;
+        ",
+    );
+}
+
+#[test]
+fn parse_fixture_allows_root_overlapping_file() {
+    let parsed = parse_fixture(
+        r"
+        //- root /foo/
+        //- /foo/lib.rs
+        struct Foo;
+        ",
+    );
+    assert_eq!(2, parsed.len());
+}
+
+#[test]
+fn parse_fixture_gets_root_cfg() {
+    let parsed = parse_fixture(
+        r"
+        //- root /foo/ cfg:feature=a
+        //- /foo/lib.rs cfg:atom
+        //- root /bar/
+        ",
+    );
+    let mut expected = CfgOptions::default();
+    expected.insert_key_value("feature".into(), "a".into());
+    assert_eq!(parsed[0].meta, FixtureMeta::Root { path: "/foo/".into(), cfg: expected });
+
+    let mut expected = CfgOptions::default();
+    expected.insert_atom("atom".into());
+    assert_eq!(Some(&expected), parsed[1].meta.cfg_options());
+    assert_eq!(Some(&CfgOptions::default()), parsed[2].meta.cfg_options());
+}
+
+const BOM: char = '\u{feff}';
+
+const EDITIONS: &[&str] = &["2015", "2018", "2021"];
+
+/// Meta which applies to all entries of a fixture. It is given on a
+/// `//- edition:2015` line before the first entry.
+#[derive(Debug, Default)]
+struct GlobalMeta {
+    /// Edition of files which don't specify one.
+    edition: Option<String>,
+}
+
+/// Global meta lines consist of `key:value` components only, while entries
+/// start with a path or `root`.
+fn is_global_meta(line: &str) -> bool {
+    split_outside_quotes(&line["//-".len()..], |c| c.is_ascii_whitespace())
+        .first()
+        .map_or(false, |it| it.contains(':'))
+}
+
+fn parse_global_meta(line: &str, line_no: usize) -> Result<GlobalMeta, FixtureError> {
+    let error = |kind: FixtureErrorKind, part: &str, message: String| FixtureError {
+        kind,
+        line: line_no,
+        column: Some(part.as_ptr() as usize - line.as_ptr() as usize + 1),
+        message,
+        line_text: Some(line.to_string()),
+    };
+
+    let mut res = GlobalMeta::default();
+    for component in split_outside_quotes(&line["//-".len()..], |c| c.is_ascii_whitespace()) {
+        match split1(component, ':') {
+            Some(("edition", value)) => {
+                if !EDITIONS.contains(&value) {
+                    return Err(error(
+                        FixtureErrorKind::InvalidValue,
+                        value,
+                        format!(
+                            "unknown edition {:?}, expected one of {}",
+                            value,
+                            EDITIONS.join(", ")
+                        ),
+                    ));
+                }
+                res.edition = Some(value.to_string());
+            }
+            _ => {
+                return Err(error(
+                    FixtureErrorKind::UnknownMetaKey,
+                    component,
+                    format!("bad component: {:?}", component),
+                ))
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[test]
+fn parse_fixture_applies_global_edition() {
+    let parsed = parse_fixture(
+        r"
+        //- edition:2015
+        //- /main.rs crate:main deps:foo
+        //- /foo/lib.rs crate:foo edition:2018
+        //- root /bar/
+        //- /bar/lib.rs crate:bar
+        ",
+    );
+    assert_eq!(4, parsed.len());
+    assert_eq!("2015", parsed[0].meta.edition().unwrap());
+    assert_eq!("2018", parsed[1].meta.edition().unwrap());
+    assert!(parsed[2].meta.edition().is_none());
+    assert_eq!("2015", parsed[3].meta.edition().unwrap());
+
+    let parsed = parse_fixture("//- /main.rs\n");
+    assert!(parsed[0].meta.edition().is_none());
+}
+
+#[test]
+fn parse_fixture_rejects_misplaced_global_meta() {
+    let err = try_parse_fixture(
+        r"
+        //- /main.rs
+        //- edition:2015
+        ",
+    )
+    .unwrap_err();
+    assert_eq!(err.kind, FixtureErrorKind::MisplacedGlobalMeta);
+    assert_eq!(err.line, 3);
+
+    let err = try_parse_fixture("//- edition:2108\n//- /main.rs").unwrap_err();
+    assert_eq!(err.kind, FixtureErrorKind::InvalidValue);
 }
 
-//- /lib.rs crate:foo deps:bar,baz cfg:foo=a,bar=b env:OUTDIR=path/to,OTHER=foo
-fn parse_meta(meta: &str) -> FixtureMeta {
-    let components = meta.split_ascii_whitespace().collect::<Vec<_>>();
+//- /lib.rs crate:foo deps:bar,baz extern-prelude:bar cfg:foo=a,bar=b env:OUTDIR=path/to,OTHER=foo bom proc-macro library
+fn parse_meta(line: &str, line_no: usize) -> Result<FixtureMeta, FixtureError> {
+    let error = |kind: FixtureErrorKind, part: Option<&str>, message: String| FixtureError {
+        kind,
+        line: line_no,
+        column: part.map(|part| part.as_ptr() as usize - line.as_ptr() as usize + 1),
+        message,
+        line_text: Some(line.to_string()),
+    };
+
+    let meta = &line["//-".len()..];
+    let components = split_outside_quotes(meta, |c| c.is_ascii_whitespace());
+    let first = match components.first() {
+        Some(it) => *it,
+        None => {
+            return Err(error(
+                FixtureErrorKind::InvalidPath,
+                None,
+                "missing path in meta line".to_string(),
+            ))
+        }
+    };
 
-    if components[0] == "root" {
-        let path: RelativePathBuf = components[1].into();
-        assert!(path.starts_with("/") && path.ends_with("/"));
-        return FixtureMeta::Root { path };
+    if first == "root" {
+        let path = match components.get(1) {
+            Some(it) => *it,
+            None => {
+                return Err(error(
+                    FixtureErrorKind::InvalidPath,
+                    Some(first),
+                    "missing root path".to_string(),
+                ))
+            }
+        };
+        if !(path.starts_with('/') && path.ends_with('/')) {
+            return Err(error(
+                FixtureErrorKind::InvalidPath,
+                Some(path),
+                "root path should start and end with `/`".to_string(),
+            ));
+        }
+        let mut cfg = CfgOptions::default();
+        for component in components[2..].iter() {
+            match split1(component, ':') {
+                Some(("cfg", value)) => parse_cfg(value, &mut cfg),
+                _ => {
+                    return Err(error(
+                        FixtureErrorKind::UnknownMetaKey,
+                        Some(component),
+                        format!("bad component: {:?}", component),
+                    ))
+                }
+            }
+        }
+        return Ok(FixtureMeta::Root { path: path.into(), cfg });
     }
 
-    let path: RelativePathBuf = components[0].into();
-    assert!(path.starts_with("/"));
+    if !first.starts_with('/') {
+        return Err(error(
+            FixtureErrorKind::InvalidPath,
+            Some(first),
+            "path should start with `/`".to_string(),
+        ));
+    }
+    let path: RelativePathBuf = first.into();
 
     let mut krate = None;
     let mut deps = Vec::new();
+    let mut extern_prelude = Vec::new();
+    let mut extern_prelude_component = None;
     let mut edition = None;
+    let mut target = None;
     let mut cfg = CfgOptions::default();
     let mut env = FxHashMap::default();
+    let mut bom = false;
+    let mut proc_macro = false;
+    let mut library = false;
     for component in components[1..].iter() {
-        let (key, value) = split1(component, ':').unwrap();
+        match *component {
+            "bom" => {
+                bom = true;
+                continue;
+            }
+            "proc-macro" => {
+                proc_macro = true;
+                continue;
+            }
+            "library" => {
+                library = true;
+                continue;
+            }
+            _ => {}
+        }
+        let (key, value) = match split1(component, ':') {
+            Some(it) => it,
+            None => {
+                return Err(error(
+                    FixtureErrorKind::UnknownMetaKey,
+                    Some(component),
+                    format!("bad component: {:?}", component),
+                ))
+            }
+        };
         match key {
             "crate" => krate = Some(value.to_string()),
-            "deps" => deps = value.split(',').map(|it| it.to_string()).collect(),
-            "edition" => edition = Some(value.to_string()),
-            "cfg" => {
-                for key in value.split(',') {
-                    match split1(key, '=') {
-                        None => cfg.insert_atom(key.into()),
-                        Some((k, v)) => cfg.insert_key_value(k.into(), v.into()),
-                    }
+            "deps" => {
+                deps = value
+                    .split(',')
+                    .map(|it| match split1(it, '=') {
+                        Some((alias, actual)) => (alias.to_string(), Some(actual.to_string())),
+                        None => (it.to_string(), None),
+                    })
+                    .collect()
+            }
+            "extern-prelude" => {
+                extern_prelude = value.split(',').map(|it| it.to_string()).collect();
+                extern_prelude_component = Some(value);
+            }
+            "edition" => {
+                if !EDITIONS.contains(&value) {
+                    return Err(error(
+                        FixtureErrorKind::InvalidValue,
+                        Some(value),
+                        format!(
+                            "unknown edition {:?}, expected one of {}",
+                            value,
+                            EDITIONS.join(", ")
+                        ),
+                    ));
                 }
+                edition = Some(value.to_string())
             }
+            "target" => target = Some(value.to_string()),
+            "cfg" => parse_cfg(value, &mut cfg),
             "env" => {
-                for key in value.split(',') {
+                for key in split_outside_quotes(value, |c| c == ',') {
                     if let Some((k, v)) = split1(key, '=') {
-                        env.insert(k.into(), v.into());
+                        env.insert(unquote(k).into(), unquote(v).into());
                     }
                 }
             }
-            _ => panic!("bad component: {:?}", component),
+            _ => {
+                return Err(error(
+                    FixtureErrorKind::UnknownMetaKey,
+                    Some(component),
+                    format!("bad component: {:?}", component),
+                ))
+            }
+        }
+    }
+
+    if let Some(missing) =
+        extern_prelude.iter().find(|it| !deps.iter().any(|(alias, _)| alias == *it))
+    {
+        return Err(error(
+            FixtureErrorKind::InvalidValue,
+            extern_prelude_component,
+            format!("extern prelude crate {:?} is not in deps", missing),
+        ));
+    }
+
+    Ok(FixtureMeta::File(FileMeta {
+        path,
+        crate_name: krate,
+        deps,
+        extern_prelude,
+        edition,
+        target,
+        cfg,
+        env,
+        bom,
+        proc_macro,
+        library,
+    }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureErrorKind {
+    /// There are no `//-` meta lines.
+    EmptyFixture,
+    /// A line is indented less than the fixture, or meta lines are indented
+    /// differently.
+    BadIndentation,
+    /// A meta line contains an unknown component.
+    UnknownMetaKey,
+    /// A meta line has a missing or malformed path.
+    InvalidPath,
+    /// Two entries have the same path.
+    DuplicatePath,
+    /// A meta component has a value outside of the allowed set.
+    InvalidValue,
+    /// A `//+` continuation line does not follow a meta line.
+    DanglingContinuation,
+    /// A crate in `deps` is not declared in the fixture.
+    UnknownDependency,
+    /// A global meta line, like `//- edition:2015`, follows an entry.
+    MisplacedGlobalMeta,
+}
+
+/// Describes a malformed fixture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixtureError {
+    pub kind: FixtureErrorKind,
+    /// 1-based line number in the fixture.
+    pub line: usize,
+    /// 1-based column in the offending line, without the fixture indentation.
+    pub column: Option<usize>,
+    pub message: String,
+    /// The offending line, without the fixture indentation.
+    pub line_text: Option<String>,
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(f, "{}:{}: {}", self.line, column, self.message)?,
+            None => write!(f, "{}: {}", self.line, self.message)?,
+        }
+        if let Some(line_text) = &self.line_text {
+            write!(f, "\n    {}", line_text)?;
+            if let Some(column) = self.column {
+                write!(f, "\n    {}^", " ".repeat(column - 1))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn fixture_error_points_at_bad_component() {
+    let err = parse_meta("//- /lib.rs crate:foo frobnicate", 3).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "3:23: bad component: \"frobnicate\"
+    //- /lib.rs crate:foo frobnicate
+                          ^"
+    );
+
+    let err = parse_meta("//- lib.rs", 1).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "1:5: path should start with `/`
+    //- lib.rs
+        ^"
+    );
+
+    let err = parse_meta("//-", 7).unwrap_err();
+    assert_eq!(err.to_string(), "7: missing path in meta line\n    //-");
+}
+
+/// Splits `text` at delimiters which are not inside double quotes, dropping
+/// empty parts. The quotes are kept, see `unquote`.
+fn split_outside_quotes(text: &str, is_delim: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut res = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (idx, c) in text.char_indices() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if !in_quotes && is_delim(c) {
+            if start < idx {
+                res.push(&text[start..idx]);
+            }
+            start = idx + c.len_utf8();
         }
     }
+    if start < text.len() {
+        res.push(&text[start..]);
+    }
+    res
+}
+
+fn unquote(text: &str) -> &str {
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        &text[1..text.len() - 1]
+    } else {
+        text
+    }
+}
 
-    FixtureMeta::File(FileMeta { path, crate_name: krate, deps, edition, cfg, env })
+fn parse_cfg(value: &str, cfg: &mut CfgOptions) {
+    for key in split_outside_quotes(value, |c| c == ',') {
+        match split1(key, '=') {
+            None => cfg.insert_atom(unquote(key).into()),
+            Some((k, v)) => cfg.insert_key_value(unquote(k).into(), unquote(v).into()),
+        }
+    }
 }
 
 fn split1(haystack: &str, delim: char) -> Option<(&str, &str)> {
@@ -346,22 +1825,24 @@ fn split1(haystack: &str, delim: char) -> Option<(&str, &str)> {
 ///                //- /foo.rs
 ///                fn bar() {}
 /// ";
-/// assert_eq!(fixture_margin(fixture),
-/// "               //- /lib.rs
+/// assert_eq!(indent_first_line(fixture),
+/// "
+///                //- /lib.rs
 ///                mod foo;
 ///                //- /foo.rs
 ///                fn bar() {}
 /// ")
 /// ```
-fn indent_first_line(fixture: &str) -> String {
+pub fn indent_first_line(fixture: &str) -> String {
     if fixture.is_empty() {
         return String::new();
     }
-    let mut lines = fixture.lines();
-    let first_line = lines.next().unwrap();
+    let (first_line, rest) = match fixture.find('\n') {
+        Some(idx) => (&fixture[..idx], &fixture[idx + 1..]),
+        None => (fixture, ""),
+    };
     if first_line.contains("//-") {
-        let rest = lines.collect::<Vec<_>>().join("\n");
-        let fixed_margin = fixture_margin(&rest);
+        let fixed_margin = fixture_margin(rest).unwrap_or_else(|| indent_len(first_line));
         let fixed_indent = fixed_margin - indent_len(first_line);
         format!("\n{}{}\n{}", " ".repeat(fixed_indent), first_line, rest)
     } else {
@@ -369,71 +1850,235 @@ fn indent_first_line(fixture: &str) -> String {
     }
 }
 
-fn fixture_margin(fixture: &str) -> usize {
-    fixture
-        .lines()
-        .filter(|it| it.trim_start().starts_with("//-"))
-        .map(indent_len)
-        .next()
-        .expect("empty fixture")
-}
+/// Returns the indentation of the first `//-` meta line, or `None` if there
+/// are no meta lines.
+///
+/// All `//-` lines of a fixture must share this indentation, and no line may
+/// be indented less than it, otherwise `parse_fixture` reports
+/// `FixtureErrorKind::BadIndentation`.
+pub fn fixture_margin(fixture: &str) -> Option<usize> {
+    fixture.lines().filter(|it| it.trim_start().starts_with("//-")).map(indent_len).next()
+}
+
+/// Length of the leading whitespace of `s`, in bytes.
+pub fn indent_len(s: &str) -> usize {
+    s.len() - s.trim_start().len()
+}
+
+#[test]
+#[should_panic]
+fn parse_fixture_checks_further_indented_metadata() {
+    parse_fixture(
+        r"
+        //- /lib.rs
+          mod bar;
+
+          fn foo() {}
+          //- /bar.rs
+          pub fn baz() {}
+          ",
+    );
+}
+
+#[test]
+fn parse_fixture_can_handle_dedented_first_line() {
+    let fixture = "//- /lib.rs
+                   mod foo;
+                   //- /foo.rs
+                   struct Bar;
+";
+    assert_eq!(
+        parse_fixture(fixture),
+        parse_fixture(
+            "//- /lib.rs
+mod foo;
+//- /foo.rs
+struct Bar;
+"
+        )
+    )
+}
+
+#[test]
+fn parse_fixture_gets_full_meta() {
+    let parsed = parse_fixture(
+        r"
+    //- /lib.rs crate:foo deps:bar,baz cfg:foo=a,bar=b,atom env:OUTDIR=path/to,OTHER=foo
+    mod m;
+    ",
+    );
+    assert_eq!(1, parsed.len());
+
+    let parsed = &parsed[0];
+    assert_eq!("mod m;\n", parsed.text);
+
+    let meta = &parsed.meta;
+    assert_eq!("foo", meta.crate_name().unwrap());
+    assert_eq!("/lib.rs", meta.path());
+    assert!(meta.cfg_options().is_some());
+    assert_eq!(2, meta.env().count());
+}
+
+#[test]
+#[should_panic(expected = "unknown edition \"2108\", expected one of 2015, 2018, 2021")]
+fn parse_fixture_rejects_unknown_edition() {
+    parse_fixture("//- /lib.rs edition:2108");
+}
+
+#[test]
+fn parse_fixture_preserves_trailing_newline() {
+    let with_newline = parse_fixture("//- /lib.rs\nmod foo;\n//- /foo.rs\nfn foo() {}\n");
+    assert_eq!("mod foo;\n", with_newline[0].text);
+    assert_eq!("fn foo() {}\n", with_newline[1].text);
+
+    let without_newline = parse_fixture("//- /lib.rs\nmod foo;\n//- /foo.rs\nfn foo() {}");
+    assert_eq!("mod foo;\n", without_newline[0].text);
+    assert_eq!("fn foo() {}", without_newline[1].text);
+}
+
+#[test]
+fn parse_fixture_parses_target() {
+    let parsed = parse_fixture(
+        r"
+        //- /lib.rs target:x86_64-unknown-linux-gnu
+        //- /foo.rs
+        ",
+    );
+    assert_eq!("x86_64-unknown-linux-gnu", parsed[0].meta.target().unwrap());
+    assert!(parsed[1].meta.target().is_none());
+}
+
+#[test]
+fn parse_fixture_supports_quoted_values() {
+    let parsed = parse_fixture(
+        r#"
+    //- /lib.rs env:KEY="a b c",OTHER=d cfg:feature="with space",atom
+    mod m;
+    "#,
+    );
+    let meta = &parsed[0].meta;
+    let env = meta.env().collect::<FxHashMap<_, _>>();
+    assert_eq!(env.len(), 2);
+    assert_eq!(env[&"KEY".to_string()], "a b c");
+    assert_eq!(env[&"OTHER".to_string()], "d");
+
+    let mut expected = CfgOptions::default();
+    expected.insert_key_value("feature".into(), "with space".into());
+    expected.insert_atom("atom".into());
+    assert_eq!(meta.cfg_options(), Some(&expected));
+}
+
+#[test]
+fn parse_fixture_gets_proc_macro_flag() {
+    let parsed = parse_fixture(
+        r"
+    //- /lib.rs crate:foo proc-macro
+    pub fn derive() {}
+    //- /main.rs crate:main deps:foo
+    ",
+    );
+    assert_eq!(2, parsed.len());
+
+    let meta = &parsed[0].meta;
+    assert!(meta.proc_macro());
+    assert_eq!("/lib.rs", meta.path());
+    assert_eq!("foo", meta.crate_name().unwrap());
 
-fn indent_len(s: &str) -> usize {
-    s.len() - s.trim_start().len()
+    let meta = &parsed[1].meta;
+    assert!(!meta.proc_macro());
+    assert_eq!("main", meta.crate_name().unwrap());
 }
 
 #[test]
-#[should_panic]
-fn parse_fixture_checks_further_indented_metadata() {
-    parse_fixture(
+fn parse_fixture_gets_library_flag() {
+    let parsed = parse_fixture(
         r"
-        //- /lib.rs
-          mod bar;
-
-          fn foo() {}
-          //- /bar.rs
-          pub fn baz() {}
-          ",
+    //- /main.rs crate:main deps:std
+    //- /std/lib.rs crate:std library
+    //- /other/lib.rs library
+    ",
     );
+    assert_eq!(3, parsed.len());
+
+    assert!(!parsed[0].meta.library());
+
+    let meta = &parsed[1].meta;
+    assert!(meta.library());
+    assert_eq!("std", meta.crate_name().unwrap());
+
+    let meta = &parsed[2].meta;
+    assert!(meta.library());
+    assert_eq!("/other/lib.rs", meta.path());
+    assert!(meta.crate_name().is_none());
+    assert!(meta.edition().is_none());
+    assert_eq!(Some(&CfgOptions::default()), meta.cfg_options());
 }
 
 #[test]
-fn parse_fixture_can_handle_dedented_first_line() {
-    let fixture = "//- /lib.rs
-                   mod foo;
-                   //- /foo.rs
-                   struct Bar;
-";
+fn parse_fixture_gets_renamed_deps() {
+    let parsed = parse_fixture(
+        r"
+    //- /main.rs crate:main deps:foo,bar=real_bar,std
+    //- /foo.rs crate:foo
+    ",
+    );
     assert_eq!(
-        parse_fixture(fixture),
-        parse_fixture(
-            "//- /lib.rs
-mod foo;
-//- /foo.rs
-struct Bar;
-"
-        )
+        [
+            ("foo".to_string(), None),
+            ("bar".to_string(), Some("real_bar".to_string())),
+            ("std".to_string(), None),
+        ],
+        parsed[0].meta.deps()
+    );
+    assert!(parsed[1].meta.deps().is_empty());
+}
+
+#[test]
+fn parse_crate_graph_resolves_renamed_deps() {
+    let (_, crates) = parse_crate_graph(
+        r"
+        //- /main.rs crate:main deps:bar=real_bar
+        //- /bar/lib.rs crate:real_bar
+        ",
     )
+    .unwrap();
+    assert_eq!(crates[0].deps, vec![1]);
 }
 
 #[test]
-fn parse_fixture_gets_full_meta() {
+fn parse_fixture_gets_extern_prelude() {
     let parsed = parse_fixture(
         r"
-    //- /lib.rs crate:foo deps:bar,baz cfg:foo=a,bar=b,atom env:OUTDIR=path/to,OTHER=foo
-    mod m;
+    //- /main.rs crate:main extern-prelude:core,alloc deps:core,alloc,foo
+    //- /foo.rs crate:foo
     ",
     );
-    assert_eq!(1, parsed.len());
+    assert_eq!(["core", "alloc"], parsed[0].meta.extern_prelude());
+    assert!(parsed[1].meta.extern_prelude().is_empty());
+}
 
-    let parsed = &parsed[0];
-    assert_eq!("mod m;\n\n", parsed.text);
+#[test]
+#[should_panic(expected = "extern prelude crate \"alloc\" is not in deps")]
+fn parse_fixture_rejects_extern_prelude_without_dep() {
+    parse_fixture("//- /main.rs crate:main deps:core extern-prelude:core,alloc");
+}
 
-    let meta = &parsed.meta;
-    assert_eq!("foo", meta.crate_name().unwrap());
-    assert_eq!("/lib.rs", meta.path());
-    assert!(meta.cfg_options().is_some());
-    assert_eq!(2, meta.env().count());
+#[test]
+fn parse_fixture_prepends_bom() {
+    let parsed = parse_fixture(
+        r"
+    //- /lib.rs bom
+    mod m;
+    //- /m.rs
+    struct S;
+    ",
+    );
+    assert_eq!(2, parsed.len());
+    assert!(parsed[0].meta.bom());
+    assert!(parsed[0].text.as_bytes().starts_with(&[0xEF, 0xBB, 0xBF]));
+    assert_eq!("\u{feff}mod m;\n", parsed[0].text);
+    assert!(!parsed[1].meta.bom());
+    assert_eq!("struct S;\n", parsed[1].text);
 }
 
 /// Same as `parse_fixture`, except it allow empty fixture
@@ -487,11 +2132,105 @@ fn lines_match_works() {
     assert!(!lines_match("b", "cb"));
 }
 
+/// Compares multi-line text with an expected pattern, applying `lines_match`
+/// to each pair of lines.
+/// - A line consisting of just `[..]` matches any number of lines, including
+///   none. Without such lines, the line counts have to be equal.
+pub fn blocks_match(expected: &str, actual: &str) -> bool {
+    let expected: Vec<&str> = expected.split('\n').collect();
+    let actual: Vec<&str> = actual.split('\n').collect();
+    return go(&expected, &actual);
+
+    fn go(expected: &[&str], actual: &[&str]) -> bool {
+        let (line, expected_rest) = match expected.split_first() {
+            Some(it) => it,
+            None => return actual.is_empty(),
+        };
+        if line.trim() == "[..]" {
+            return (0..=actual.len()).any(|skip| go(expected_rest, &actual[skip..]));
+        }
+        match actual.split_first() {
+            Some((actual_line, actual_rest)) => {
+                lines_match(line, actual_line) && go(expected_rest, actual_rest)
+            }
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn blocks_match_works() {
+    assert!(blocks_match("a\nb[..]", "a\nb c"));
+    assert!(!blocks_match("a\nb", "a\nb\nc"));
+    assert!(!blocks_match("a\nb\nc", "a\nb"));
+    assert!(!blocks_match("a\nc", "a\nb"));
+}
+
+#[test]
+fn blocks_match_skips_lines() {
+    assert!(blocks_match("a\n[..]\nd", "a\nb\nc\nd"));
+    assert!(blocks_match("a\n[..]\nd", "a\nd"));
+    assert!(blocks_match("a\n[..]", "a\nb\nc"));
+    assert!(blocks_match("[..]\nc[..]\n[..]", "a\nb\ncd\ne"));
+    // Lines after the skipped ones still have to match.
+    assert!(!blocks_match("a\n[..]\nd", "a\nb\nc"));
+    assert!(!blocks_match("[..]\nx", "a\nb"));
+}
+
+/// Same as `lines_match`, but `expected` is a regular expression which has to
+/// match the whole `actual` line. Backslashes in both strings are normalized
+/// just like in `lines_match`, so use classes like `[0-9]` instead of `\d`.
+pub fn lines_match_regex(expected: &str, actual: &str) -> bool {
+    let expected = expected.replace(r"\\", "/").replace(r"\", "/");
+    let actual = actual.replace(r"\\", "/").replace(r"\", "/");
+    let re = Regex::new(&format!("^(?:{})$", expected))
+        .unwrap_or_else(|err| panic!("invalid regex {:?}: {}", expected, err));
+    re.is_match(&actual)
+}
+
+#[test]
+fn lines_match_regex_works() {
+    assert!(lines_match_regex("hash [0-9a-f]{8}", "hash deadbeef"));
+    assert!(lines_match_regex("C:/dir/[a-z]+.rs", r"C:\dir\main.rs"));
+
+    // The whole line has to match.
+    assert!(!lines_match_regex("hash [0-9a-f]{8}", "hash deadbeef0"));
+    assert!(!lines_match_regex("[0-9]+", "id 42"));
+    assert!(!lines_match_regex("a|b", "ab"));
+}
+
 /// Compares JSON object for approximate equality.
 /// You can use `[..]` wildcard in strings (useful for OS dependent things such
 /// as paths). You can use a `"{...}"` string literal as a wildcard for
 /// arbitrary nested JSON. Arrays are sorted before comparison.
 pub fn find_mismatch<'a>(expected: &'a Value, actual: &'a Value) -> Option<(&'a Value, &'a Value)> {
+    find_mismatch_path(expected, actual).map(|(_path, l, r)| (l, r))
+}
+
+/// Same as `find_mismatch`, but also returns the path to the mismatch, like
+/// `.items[2].name`. The path is empty if the roots themselves differ.
+pub fn find_mismatch_path<'a>(
+    expected: &'a Value,
+    actual: &'a Value,
+) -> Option<(String, &'a Value, &'a Value)> {
+    find_mismatch_at(String::new(), expected, actual, false)
+}
+
+/// Same as `find_mismatch`, but arrays are compared element by element
+/// instead of being sorted, for JSON where the order is significant.
+pub fn find_mismatch_ordered<'a>(
+    expected: &'a Value,
+    actual: &'a Value,
+) -> Option<(&'a Value, &'a Value)> {
+    find_mismatch_at(String::new(), expected, actual, true).map(|(_path, l, r)| (l, r))
+}
+
+fn find_mismatch_at<'a>(
+    path: String,
+    expected: &'a Value,
+    actual: &'a Value,
+    ordered: bool,
+) -> Option<(String, &'a Value, &'a Value)> {
     use serde_json::Value::*;
     match (expected, actual) {
         (&Number(ref l), &Number(ref r)) if l == r => None,
@@ -499,13 +2238,24 @@ pub fn find_mismatch<'a>(expected: &'a Value, actual: &'a Value) -> Option<(&'a
         (&String(ref l), &String(ref r)) if lines_match(l, r) => None,
         (&Array(ref l), &Array(ref r)) => {
             if l.len() != r.len() {
-                return Some((expected, actual));
+                return Some((path, expected, actual));
+            }
+
+            if ordered {
+                return l
+                    .iter()
+                    .zip(r.iter())
+                    .enumerate()
+                    .filter_map(|(idx, (l, r))| {
+                        find_mismatch_at(format!("{}[{}]", path, idx), l, r, ordered)
+                    })
+                    .next();
             }
 
-            let mut l = l.iter().collect::<Vec<_>>();
+            let mut l = l.iter().enumerate().collect::<Vec<_>>();
             let mut r = r.iter().collect::<Vec<_>>();
 
-            l.retain(|l| match r.iter().position(|r| find_mismatch(l, r).is_none()) {
+            l.retain(|(_, l)| match r.iter().position(|r| find_mismatch(l, r).is_none()) {
                 Some(i) => {
                     r.remove(i);
                     false
@@ -515,7 +2265,8 @@ pub fn find_mismatch<'a>(expected: &'a Value, actual: &'a Value) -> Option<(&'a
 
             if !l.is_empty() {
                 assert!(!r.is_empty());
-                Some((&l[0], &r[0]))
+                let (idx, l) = l[0];
+                Some((format!("{}[{}]", path, idx), l, r[0]))
             } else {
                 assert_eq!(r.len(), 0);
                 None
@@ -524,18 +2275,72 @@ pub fn find_mismatch<'a>(expected: &'a Value, actual: &'a Value) -> Option<(&'a
         (&Object(ref l), &Object(ref r)) => {
             let same_keys = l.len() == r.len() && l.keys().all(|k| r.contains_key(k));
             if !same_keys {
-                return Some((expected, actual));
+                return Some((path, expected, actual));
             }
 
-            l.values().zip(r.values()).filter_map(|(l, r)| find_mismatch(l, r)).next()
+            l.iter()
+                .zip(r.values())
+                .filter_map(|((key, l), r)| {
+                    find_mismatch_at(format!("{}.{}", path, key), l, r, ordered)
+                })
+                .next()
         }
         (&Null, &Null) => None,
         // magic string literal "{...}" acts as wildcard for any sub-JSON
         (&String(ref l), _) if l == "{...}" => None,
-        _ => Some((expected, actual)),
+        _ => Some((path, expected, actual)),
     }
 }
 
+#[test]
+fn find_mismatch_ordered_respects_order() {
+    let expected = serde_json::json!([1, 2]);
+    let actual = serde_json::json!([2, 1]);
+    assert!(find_mismatch(&expected, &actual).is_none());
+    assert_eq!(
+        find_mismatch_ordered(&expected, &actual),
+        Some((&serde_json::json!(1), &serde_json::json!(2)))
+    );
+
+    let expected = serde_json::json!([{ "path": "[..]/a.rs" }, "{...}"]);
+    let actual = serde_json::json!([{ "path": "/tmp/a.rs" }, [1, 2]]);
+    assert!(find_mismatch_ordered(&expected, &actual).is_none());
+}
+
+#[test]
+fn find_mismatch_path_works() {
+    let expected = serde_json::json!({
+        "items": [{ "name": "a" }, { "name": "b" }, { "name": "[..]c" }],
+        "meta": "{...}",
+    });
+    let actual = serde_json::json!({
+        "items": [{ "name": "b" }, { "name": "a" }, { "name": "xd" }],
+        "meta": { "anything": [1, 2] },
+    });
+    let (path, l, r) = find_mismatch_path(&expected, &actual).unwrap();
+    assert_eq!(path, ".items[2]");
+    assert_eq!(l, &serde_json::json!({ "name": "[..]c" }));
+    assert_eq!(r, &serde_json::json!({ "name": "xd" }));
+
+    let expected = serde_json::json!({ "a": { "b": [1, 2] } });
+    let actual = serde_json::json!({ "a": { "b": [1, 2, 3] } });
+    assert_eq!(find_mismatch_path(&expected, &actual).unwrap().0, ".a.b");
+
+    assert!(find_mismatch_path(&expected, &expected).is_none());
+}
+
+/// `Sync` if dir tests run on multiple threads, that is with the `parallel`
+/// feature, and implemented for every type otherwise.
+#[cfg(feature = "parallel")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSync for T {}
+
 /// Calls callback `f` with input code and file paths for each `.rs` file in `test_data_dir`
 /// subdirectories defined by `paths`.
 ///
@@ -545,22 +2350,316 @@ pub fn find_mismatch<'a>(expected: &'a Value, actual: &'a Value) -> Option<(&'a
 /// If there is no matching output file it will be created and filled with the
 /// output of `f()`, but the test will fail.
 pub fn dir_tests<F>(test_data_dir: &Path, paths: &[&str], outfile_extension: &str, f: F)
+where
+    F: Fn(&str, &Path) -> String + MaybeSync,
+{
+    dir_tests_transform(test_data_dir, paths, outfile_extension, f, |it| it.to_string())
+}
+
+/// Same as `dir_tests`, but `transform` is applied to both the expected and
+/// the actual output before comparing them. This is useful to scrub
+/// run-specific noise. Rewritten expectations are transformed as well.
+pub fn dir_tests_transform<F, T>(
+    test_data_dir: &Path,
+    paths: &[&str],
+    outfile_extension: &str,
+    f: F,
+    transform: T,
+) where
+    F: Fn(&str, &Path) -> String + MaybeSync,
+    T: Fn(&str) -> String + MaybeSync,
+{
+    check_dir_test_files(
+        collect_rust_files(test_data_dir, paths),
+        outfile_extension,
+        &RewritePolicy::from_env(),
+        f,
+        transform,
+    )
+}
+
+/// Same as `dir_tests`, but also picks up `.rs` files in nested
+/// subdirectories of `paths`.
+pub fn dir_tests_recursive<F>(test_data_dir: &Path, paths: &[&str], outfile_extension: &str, f: F)
+where
+    F: Fn(&str, &Path) -> String + MaybeSync,
+{
+    check_dir_test_files(
+        collect_rust_files_recursive(test_data_dir, paths),
+        outfile_extension,
+        &RewritePolicy::from_env(),
+        f,
+        |it| it.to_string(),
+    )
+}
+
+fn check_dir_test_files<F, T>(
+    files: Vec<(PathBuf, String)>,
+    outfile_extension: &str,
+    policy: &RewritePolicy,
+    f: F,
+    transform: T,
+) where
+    F: Fn(&str, &Path) -> String + MaybeSync,
+    T: Fn(&str) -> String + MaybeSync,
+{
+    // Returns whether the expected output was rewritten.
+    let check = |(path, input_code): &(PathBuf, String)| {
+        let path = path.with_extension(outfile_extension);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let actual = transform(&f(&input_code, &path));
+            if !path.exists() {
+                if policy.should_rewrite(&path) {
+                    write_text(&path, &actual, NewlineStyle::from_env());
+                    return true;
+                }
+                println!("\nfile: {}", path.display());
+                println!("No .txt file with expected result, creating...\n");
+                println!("{}\n{}", input_code, actual);
+                write_text(&path, &actual, NewlineStyle::from_env());
+                panic!("No expected result");
+            }
+            let expected = transform(&read_text(&path));
+            assert_equal_text(&expected, &actual, &path, policy)
+        }));
+        (path, res)
+    };
+
+    #[cfg(feature = "parallel")]
+    let results: Vec<_> = {
+        use rayon::prelude::*;
+        files.par_iter().map(check).collect()
+    };
+    #[cfg(not(feature = "parallel"))]
+    let results: Vec<_> = files.iter().map(check).collect();
+
+    let mut rewritten = Vec::new();
+    let mut failures = Vec::new();
+    for (path, res) in results {
+        match res {
+            Ok(true) => rewritten.push(path),
+            Ok(false) => (),
+            Err(payload) => failures.push((path, payload)),
+        }
+    }
+    if !rewritten.is_empty() {
+        println!("\nrewritten {} file(s):", rewritten.len());
+        for path in &rewritten {
+            println!("  {}", path.display());
+        }
+    }
+
+    // Failures are reported in file order regardless of how the files were
+    // processed. A single failure is re-raised as is.
+    match failures.len() {
+        0 => (),
+        1 => std::panic::resume_unwind(failures.pop().unwrap().1),
+        n => {
+            let report = failures
+                .iter()
+                .map(|(path, payload)| format!("{}: {}", path.display(), panic_message(&**payload)))
+                .collect::<Vec<_>>()
+                .join("\n");
+            panic!("{} dir tests failed:\n{}", n, report)
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+/// A fresh directory under the system temp dir, removed on drop.
+#[cfg(test)]
+struct TempDir {
+    path: PathBuf,
+}
+
+#[cfg(test)]
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let path =
+            std::env::temp_dir().join(format!("test_utils_{}_{}_{}", name, std::process::id(), id));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[test]
+fn dir_tests_report_all_failures() {
+    let dir = TempDir::new("dir_tests_report_all_failures");
+    let data_dir = dir.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    for name in &["a", "b", "c"] {
+        fs::write(data_dir.join(format!("{}.rs", name)), "fn f() {}").unwrap();
+    }
+    fs::write(data_dir.join("a.txt"), "wrong").unwrap();
+    fs::write(data_dir.join("b.txt"), "FN F() {}").unwrap();
+
+    let res = std::panic::catch_unwind(|| {
+        dir_tests(&dir, &["data"], "txt", |text, _| text.to_uppercase())
+    });
+    let payload = res.unwrap_err();
+    let message = panic_message(&*payload);
+    assert!(message.starts_with("2 dir tests failed:\n"), "{}", message);
+    assert!(message.contains("a.txt: text differs\n"), "{}", message);
+    assert!(message.ends_with("c.txt: No expected result"), "{}", message);
+    // Missing expectations are still created.
+    assert_eq!(read_text(&data_dir.join("c.txt")), "FN F() {}");
+}
+
+#[test]
+fn dir_tests_transform_scrubs_noise() {
+    let dir = TempDir::new("dir_tests_transform");
+    let data_dir = dir.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    fs::write(data_dir.join("a.rs"), "fn a() {}").unwrap();
+    fs::write(data_dir.join("a.txt"), "fn a() {} took 17ms").unwrap();
+
+    let mask_numbers = |text: &str| {
+        text.split(' ')
+            .map(|word| if word.ends_with("ms") { "<N>ms" } else { word })
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    dir_tests_transform(
+        &dir,
+        &["data"],
+        "txt",
+        |text, _| format!("{} took 92ms", text),
+        mask_numbers,
+    );
+}
+
+#[test]
+#[cfg(not(feature = "parallel"))]
+fn dir_tests_accept_non_sync_callbacks() {
+    let dir = TempDir::new("dir_tests_non_sync");
+    let data_dir = dir.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    fs::write(data_dir.join("a.rs"), "fn a() {}").unwrap();
+    fs::write(data_dir.join("a.txt"), "fn a() {}").unwrap();
+
+    let calls = std::cell::Cell::new(0);
+    dir_tests(&dir, &["data"], "txt", |text, _| {
+        calls.set(calls.get() + 1);
+        text.to_string()
+    });
+    assert_eq!(calls.get(), 1);
+}
+
+#[test]
+fn dir_tests_rewrite_respects_filter() {
+    let dir = TempDir::new("dir_tests_rewrite_respects_filter");
+    let data_dir = dir.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    for name in &["blessed", "other"] {
+        fs::write(data_dir.join(format!("{}.rs", name)), "fn f() {}").unwrap();
+        fs::write(data_dir.join(format!("{}.txt", name)), "stale").unwrap();
+    }
+    fs::write(data_dir.join("blessed_new.rs"), "fn g() {}").unwrap();
+
+    let policy = RewritePolicy { update: true, filter: Some("blessed".to_string()) };
+    let res = std::panic::catch_unwind(|| {
+        check_dir_test_files(
+            collect_rust_files(&dir, &["data"]),
+            "txt",
+            &policy,
+            |text, _| text.to_uppercase(),
+            |it| it.to_string(),
+        )
+    });
+
+    assert!(res.is_err(), "`other.txt` should still mismatch");
+    assert_eq!(read_text(&data_dir.join("blessed.txt")), "FN F() {}");
+    assert_eq!(read_text(&data_dir.join("blessed_new.txt")), "FN G() {}");
+    assert_eq!(read_text(&data_dir.join("other.txt")), "stale");
+}
+
+/// A file whose content doesn't match the output of the callback, as reported
+/// by `dir_tests_report`.
+#[derive(Debug)]
+pub struct Mismatch {
+    /// Path to the file with the expected output.
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: String,
+    pub diff: String,
+}
+
+/// Same as `dir_tests`, but instead of failing the test it returns the list
+/// of all mismatches. Expected output files are never created or rewritten.
+pub fn dir_tests_report<F>(
+    test_data_dir: &Path,
+    paths: &[&str],
+    outfile_extension: &str,
+    f: F,
+) -> Vec<Mismatch>
 where
     F: Fn(&str, &Path) -> String,
 {
+    let mut res = Vec::new();
     for (path, input_code) in collect_rust_files(test_data_dir, paths) {
         let actual = f(&input_code, &path);
         let path = path.with_extension(outfile_extension);
-        if !path.exists() {
-            println!("\nfile: {}", path.display());
-            println!("No .txt file with expected result, creating...\n");
-            println!("{}\n{}", input_code, actual);
-            fs::write(&path, &actual).unwrap();
-            panic!("No expected result");
+        let expected = if path.exists() { read_text(&path) } else { String::new() };
+        if expected != actual {
+            let diff = __Changeset::new(&expected, &actual, "\n").to_string();
+            res.push(Mismatch { path, expected, actual, diff });
         }
-        let expected = read_text(&path);
-        assert_equal_text(&expected, &actual, &path);
     }
+    res
+}
+
+#[test]
+fn dir_tests_report_lists_all_mismatches() {
+    let dir = TempDir::new("dir_tests_report");
+    let data_dir = dir.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    for (name, input, expected) in
+        &[("a", "fn a() {}", "FN A() {}"), ("b", "fn b() {}", "fn b() {}"), ("c", "fn c() {}", "")]
+    {
+        fs::write(data_dir.join(format!("{}.rs", name)), input).unwrap();
+        fs::write(data_dir.join(format!("{}.txt", name)), expected).unwrap();
+    }
+
+    let mismatches = dir_tests_report(&dir, &["data"], "txt", |text, _| text.to_uppercase());
+    let paths = mismatches.iter().map(|it| it.path.clone()).collect::<Vec<_>>();
+    assert_eq!(paths, vec![data_dir.join("b.txt"), data_dir.join("c.txt")]);
+    assert_eq!(mismatches[0].actual, "FN B() {}");
+    assert_eq!(read_text(&data_dir.join("c.txt")), "");
 }
 
 /// Collects all `.rs` files from `dir` subdirectories defined by `paths`.
@@ -578,6 +2677,131 @@ pub fn collect_rust_files(root_dir: &Path, paths: &[&str]) -> Vec<(PathBuf, Stri
         .collect()
 }
 
+/// Same as `collect_rust_files`, but also reads the sibling of each `.rs`
+/// file with the `sibling_extension`, like `foo.diag` for `foo.rs`, if it
+/// exists.
+pub fn collect_rust_files_with_sibling(
+    root_dir: &Path,
+    paths: &[&str],
+    sibling_extension: &str,
+) -> Vec<(PathBuf, String, Option<String>)> {
+    collect_rust_files(root_dir, paths)
+        .into_iter()
+        .map(|(path, text)| {
+            let sibling = path.with_extension(sibling_extension);
+            let sibling = if sibling.exists() { Some(read_text(&sibling)) } else { None };
+            (path, text, sibling)
+        })
+        .collect()
+}
+
+#[test]
+fn collect_rust_files_with_sibling_reads_optional_siblings() {
+    let dir = TempDir::new("collect_rust_files_with_sibling");
+    let data_dir = dir.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    fs::write(data_dir.join("a.rs"), "fn a() {}").unwrap();
+    fs::write(data_dir.join("a.diag"), "error\r\n").unwrap();
+    fs::write(data_dir.join("b.rs"), "fn b() {}").unwrap();
+
+    let files = collect_rust_files_with_sibling(&dir, &["data"], "diag");
+    assert_eq!(
+        files,
+        vec![
+            (data_dir.join("a.rs"), "fn a() {}".to_string(), Some("error\n".to_string())),
+            (data_dir.join("b.rs"), "fn b() {}".to_string(), None),
+        ]
+    );
+}
+
+/// Same as `collect_rust_files`, but walks subdirectories as well. The files
+/// are sorted by path across the whole tree.
+pub fn collect_rust_files_recursive(root_dir: &Path, paths: &[&str]) -> Vec<(PathBuf, String)> {
+    let mut files = Vec::new();
+    let mut visited = FxHashSet::default();
+    for path in paths {
+        rust_files_in_dir_recursive(&root_dir.join(path), &mut visited, &mut files);
+    }
+    files.sort();
+    files
+        .into_iter()
+        .map(|path| {
+            let text = read_text(&path);
+            (path, text)
+        })
+        .collect()
+}
+
+/// Directories are deduplicated by their canonical path, so symlink loops
+/// are only walked once.
+fn rust_files_in_dir_recursive(
+    dir: &Path,
+    visited: &mut FxHashSet<PathBuf>,
+    acc: &mut Vec<PathBuf>,
+) {
+    if !visited.insert(dir.canonicalize().unwrap()) {
+        return;
+    }
+    for file in fs::read_dir(&dir).unwrap() {
+        let path = file.unwrap().path();
+        if path.is_dir() {
+            rust_files_in_dir_recursive(&path, visited, acc);
+        } else if path.extension().unwrap_or_default() == "rs" {
+            acc.push(path);
+        }
+    }
+}
+
+#[test]
+fn collect_rust_files_recursive_walks_subdirectories() {
+    let dir = TempDir::new("collect_rust_files_recursive");
+    let data_dir = dir.join("data");
+    fs::create_dir_all(data_dir.join("nested/deeper")).unwrap();
+    fs::write(data_dir.join("b.rs"), "fn b() {}").unwrap();
+    fs::write(data_dir.join("nested/a.rs"), "fn a() {}").unwrap();
+    fs::write(data_dir.join("nested/deeper/c.rs"), "fn c() {}").unwrap();
+    fs::write(data_dir.join("nested/notes.md"), "").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&data_dir, data_dir.join("nested/loop")).unwrap();
+
+    let files = collect_rust_files_recursive(&dir, &["data"]);
+    let paths = files.into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        vec![
+            data_dir.join("b.rs"),
+            data_dir.join("nested/a.rs"),
+            data_dir.join("nested/deeper/c.rs"),
+        ]
+    );
+
+    fs::write(data_dir.join("b.txt"), "FN B() {}").unwrap();
+    fs::write(data_dir.join("nested/a.txt"), "FN A() {}").unwrap();
+    fs::write(data_dir.join("nested/deeper/c.txt"), "FN C() {}").unwrap();
+    let seen = std::sync::Mutex::new(Vec::new());
+    let f = |text: &str, path: &Path| {
+        seen.lock().unwrap().push(path.to_path_buf());
+        text.to_uppercase()
+    };
+    dir_tests_recursive(&dir, &["data"], "txt", f);
+    let mut seen = seen.into_inner().unwrap();
+    seen.sort();
+    assert_eq!(
+        seen,
+        vec![
+            data_dir.join("b.txt"),
+            data_dir.join("nested/a.txt"),
+            data_dir.join("nested/deeper/c.txt"),
+        ]
+    );
+
+    fs::write(data_dir.join("nested/deeper/c.txt"), "FN D() {}").unwrap();
+    let res = std::panic::catch_unwind(|| {
+        dir_tests_recursive(&dir, &["data"], "txt", |text, _| text.to_uppercase())
+    });
+    assert!(res.is_err());
+}
+
 /// Collects paths to all `.rs` files from `dir` in a sorted `Vec<PathBuf>`.
 fn rust_files_in_dir(dir: &Path) -> Vec<PathBuf> {
     let mut acc = Vec::new();
@@ -593,12 +2817,50 @@ fn rust_files_in_dir(dir: &Path) -> Vec<PathBuf> {
 }
 
 /// Returns the path to the root directory of `rust-analyzer` project.
+///
+/// This is the `CARGO_WORKSPACE_DIR` env var if it is set, or the closest
+/// directory with a workspace `Cargo.toml` above this crate's manifest or
+/// the current executable. As a last resort, it is assumed that this crate
+/// lives in `crates/test_utils`.
 pub fn project_dir() -> PathBuf {
-    let dir = env!("CARGO_MANIFEST_DIR");
-    PathBuf::from(dir).parent().unwrap().parent().unwrap().to_owned()
+    if let Some(dir) = std::env::var_os("CARGO_WORKSPACE_DIR") {
+        return PathBuf::from(dir);
+    }
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    if let Some(dir) = find_workspace_root(manifest_dir) {
+        return dir;
+    }
+    let exe = std::env::current_exe().ok();
+    if let Some(dir) = exe.as_deref().and_then(find_workspace_root) {
+        return dir;
+    }
+    manifest_dir.parent().unwrap().parent().unwrap().to_owned()
+}
+
+/// Finds the closest ancestor of `path` (or `path` itself) with a
+/// `Cargo.toml` which has a `[workspace]` section.
+fn find_workspace_root(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .find(|dir| {
+            fs::read_to_string(dir.join("Cargo.toml"))
+                .map_or(false, |text| text.lines().any(|line| line.trim() == "[workspace]"))
+        })
+        .map(|dir| dir.to_owned())
+}
+
+#[test]
+fn find_workspace_root_skips_member_manifests() {
+    let dir = TempDir::new("find_workspace_root");
+    let member = dir.join("vendor/nested/crates/foo");
+    fs::create_dir_all(member.join("src")).unwrap();
+    fs::write(dir.join("Cargo.toml"), "[workspace]\nmembers = []\n").unwrap();
+    fs::write(member.join("Cargo.toml"), "[package]\nname = \"foo\"\n").unwrap();
+
+    assert_eq!(find_workspace_root(&member.join("src")), Some(dir.to_path_buf()));
+    assert_eq!(find_workspace_root(&dir), Some(dir.to_path_buf()));
 }
 
-/// Read file and normalize newlines.
+/// Read file, strip a leading byte-order mark and normalize newlines.
 ///
 /// `rustc` seems to always normalize `\r\n` newlines to `\n`:
 ///
@@ -610,16 +2872,87 @@ pub fn project_dir() -> PathBuf {
 ///
 /// so this should always be correct.
 pub fn read_text(path: &Path) -> String {
-    fs::read_to_string(path)
-        .unwrap_or_else(|_| panic!("File at {:?} should be valid", path))
-        .replace("\r\n", "\n")
+    let text =
+        fs::read_to_string(path).unwrap_or_else(|_| panic!("File at {:?} should be valid", path));
+    let text = if text.starts_with(BOM) { &text[BOM.len_utf8()..] } else { &text };
+    text.replace("\r\n", "\n")
+}
+
+#[test]
+fn read_text_strips_bom() {
+    let dir = TempDir::new("read_text_strips_bom");
+    let path = dir.join("main.rs");
+    fs::write(&path, b"\xEF\xBB\xBFfn main() {}\r\n").unwrap();
+    assert_eq!(read_text(&path), "fn main() {}\n");
+}
+
+/// Newline style used when writing expectation files.
+///
+/// Defaults to `\n`, can be overridden with `RA_TEST_NEWLINE` env var, which
+/// accepts `lf`, `crlf` or `native`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewlineStyle {
+    Lf,
+    CrLf,
+}
+
+impl NewlineStyle {
+    pub fn from_env() -> NewlineStyle {
+        match std::env::var("RA_TEST_NEWLINE").unwrap_or_default().as_str() {
+            "crlf" => NewlineStyle::CrLf,
+            "native" if cfg!(windows) => NewlineStyle::CrLf,
+            _ => NewlineStyle::Lf,
+        }
+    }
+
+    fn apply(self, text: &str) -> String {
+        match self {
+            NewlineStyle::Lf => text.to_string(),
+            NewlineStyle::CrLf => text.replace('\n', "\r\n"),
+        }
+    }
+}
+
+/// Writes `text`, which uses `\n` newlines, to the file at `path` converting
+/// newlines to the given style.
+pub fn write_text(path: &Path, text: &str, newlines: NewlineStyle) {
+    fs::write(path, newlines.apply(text)).unwrap()
+}
+
+#[test]
+fn write_text_uses_newline_style() {
+    let dir = TempDir::new("write_text_uses_newline_style");
+    let path = dir.join("a.txt");
+
+    write_text(&path, "a\nb\n", NewlineStyle::CrLf);
+    assert_eq!(fs::read(&path).unwrap(), b"a\r\nb\r\n");
+    assert_eq!(read_text(&path), "a\nb\n");
+
+    write_text(&path, "a\nb\n", NewlineStyle::Lf);
+    assert_eq!(fs::read(&path).unwrap(), b"a\nb\n");
 }
 
 /// Returns `false` if slow tests should not run, otherwise returns `true` and
 /// also creates a file at `./target/.slow_tests_cookie` which serves as a flag
 /// that slow tests did run.
 pub fn skip_slow_tests() -> bool {
-    let should_skip = std::env::var("CI").is_err() && std::env::var("RUN_SLOW_TESTS").is_err();
+    skip_slow_test(None)
+}
+
+/// Same as `skip_slow_tests`, but the test called `name` also runs if it
+/// matches the `SLOW_TESTS` env var. The pattern supports `[..]` wildcards,
+/// like in `lines_match`.
+pub fn skip_slow_tests_except(name: &str) -> bool {
+    skip_slow_test(Some(name))
+}
+
+fn skip_slow_test(name: Option<&str>) -> bool {
+    let allowed = match (name, std::env::var("SLOW_TESTS")) {
+        (Some(name), Ok(pattern)) => lines_match(&pattern, name),
+        _ => false,
+    };
+    let should_skip =
+        !allowed && std::env::var("CI").is_err() && std::env::var("RUN_SLOW_TESTS").is_err();
     if should_skip {
         eprintln!("ignoring slow test")
     } else {
@@ -629,27 +2962,58 @@ pub fn skip_slow_tests() -> bool {
     should_skip
 }
 
-const REWRITE: bool = false;
+/// Which mismatching or missing expectations `dir_tests` rewrite instead of
+/// failing. Rewriting is requested with the `UPDATE_EXPECT` env var,
+/// optionally limited by `RA_DIR_TEST_FILTER` to files whose name contains it.
+/// Files not matching the filter are still compared.
+#[derive(Debug)]
+struct RewritePolicy {
+    update: bool,
+    filter: Option<String>,
+}
+
+impl RewritePolicy {
+    fn from_env() -> RewritePolicy {
+        RewritePolicy {
+            update: std::env::var("UPDATE_EXPECT").is_ok(),
+            filter: std::env::var("RA_DIR_TEST_FILTER").ok(),
+        }
+    }
+
+    fn should_rewrite(&self, path: &Path) -> bool {
+        if !self.update {
+            return false;
+        }
+        match &self.filter {
+            Some(filter) => {
+                path.file_name().map_or(false, |it| it.to_string_lossy().contains(filter.as_str()))
+            }
+            None => true,
+        }
+    }
+}
 
 /// Asserts that `expected` and `actual` strings are equal. If they differ only
-/// in trailing or leading whitespace the test won't fail and
-/// the contents of `actual` will be written to the file located at `path`.
-fn assert_equal_text(expected: &str, actual: &str, path: &Path) {
+/// in trailing or leading whitespace, or `policy` allows rewriting `path`, the
+/// test won't fail and the contents of `actual` will be written to the file
+/// located at `path`. Returns whether the file was rewritten.
+fn assert_equal_text(expected: &str, actual: &str, path: &Path, policy: &RewritePolicy) -> bool {
     if expected == actual {
-        return;
+        return false;
     }
     let dir = project_dir();
     let pretty_path = path.strip_prefix(&dir).unwrap_or_else(|_| path);
     if expected.trim() == actual.trim() {
         println!("whitespace difference, rewriting");
         println!("file: {}\n", pretty_path.display());
-        fs::write(path, actual).unwrap();
-        return;
+        write_text(path, actual, NewlineStyle::from_env());
+        return true;
     }
-    if REWRITE {
+    if policy.should_rewrite(path) {
         println!("rewriting {}", pretty_path.display());
-        fs::write(path, actual).unwrap();
-        return;
+        write_text(path, actual, NewlineStyle::from_env());
+        return true;
     }
     assert_eq_text!(expected, actual, "file: {}", pretty_path.display());
+    false
 }